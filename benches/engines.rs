@@ -0,0 +1,39 @@
+// Times the tree-walking evaluator against the bytecode vm on a
+// recursive fibonacci, which is mostly calls and integer arithmetic.
+// Compiling is timed along with running, as a script would pay for both.
+//
+//     cargo bench --bench engines
+
+use monkey::{compiler::compile, eval::eval_program, object::Environment, parser::parse, vm};
+use std::cell::RefCell;
+use std::rc::Rc;
+use std::time::Instant;
+
+const FIB: &str = "let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } };";
+
+fn main() {
+    println!("{:>4} {:>12} {:>12} {:>8}", "n", "eval", "vm", "speedup");
+    for n in &[15, 20, 25] {
+        let (program, errors) = parse(&format!("{} fib({});", FIB, n));
+        assert!(errors.is_empty(), "parser had errors: {:#?}", errors);
+
+        let start = Instant::now();
+        let env = Rc::new(RefCell::new(Environment::new()));
+        let evaluated = eval_program(&program, &env);
+        let eval_time = start.elapsed();
+
+        let start = Instant::now();
+        let bytecode = compile(&program).expect("compile error");
+        let run = vm::run(bytecode).expect("runtime error");
+        let vm_time = start.elapsed();
+
+        assert_eq!(evaluated, run);
+        println!(
+            "{:>4} {:>12?} {:>12?} {:>7.1}x",
+            n,
+            eval_time,
+            vm_time,
+            eval_time.as_secs_f64() / vm_time.as_secs_f64()
+        );
+    }
+}
@@ -0,0 +1,78 @@
+// Times the lexer over generated scripts of growing size. Lexing is
+// linear when doubling the input roughly doubles the time per run,
+// which shows up as a flat ns/byte column. The borrowing lexer hands
+// out slices of the script instead of copying each literal.
+//
+//     cargo bench --bench lexer
+
+use monkey::{lexer::Lexer, token::TokenType};
+use std::time::{Duration, Instant};
+
+const SNIPPET: &str = "let add = fn(x, y) { x + y; };
+let result = add(five, ten) * 2 / 7 - 1;
+if (result != 10) { return \"naïve café ☕\"; } else { return [1, 2, 3][0]; }
+let map = {\"one\": 1, \"two\": 2};
+";
+
+fn script_of_size(bytes: usize) -> String {
+    let mut script = String::with_capacity(bytes + SNIPPET.len());
+    while script.len() < bytes {
+        script.push_str(SNIPPET);
+    }
+    script
+}
+
+fn lex_all(input: &str) -> usize {
+    let mut lexer = Lexer::new(input.to_string());
+    let mut count = 0;
+    while lexer.next_token().kind != TokenType::EOF {
+        count += 1;
+    }
+    count
+}
+
+fn lex_all_borrowed(input: &str) -> usize {
+    let mut lexer = Lexer::new(input);
+    let mut count = 0;
+    while lexer.next_token_ref().kind != TokenType::EOF {
+        count += 1;
+    }
+    count
+}
+
+fn time_runs(runs: u32, script: &str, lex: fn(&str) -> usize) -> (usize, Duration) {
+    let mut tokens = 0;
+    let mut total = Duration::default();
+    for _ in 0..runs {
+        let start = Instant::now();
+        tokens = lex(script);
+        total += start.elapsed();
+    }
+    (tokens, total / runs)
+}
+
+fn main() {
+    const RUNS: u32 = 5;
+
+    println!(
+        "{:>10} {:>10} {:>12} {:>8} {:>12} {:>8}",
+        "bytes", "tokens", "owned/run", "ns/byte", "borrowed/run", "ns/byte"
+    );
+    for &size in &[128 * 1024, 256 * 1024, 512 * 1024, 1024 * 1024, 2048 * 1024] {
+        let script = script_of_size(size);
+
+        let (tokens, owned) = time_runs(RUNS, &script, lex_all);
+        let (_, borrowed) = time_runs(RUNS, &script, lex_all_borrowed);
+
+        let ns_per_byte = |d: Duration| d.as_nanos() as f64 / script.len() as f64;
+        println!(
+            "{:>10} {:>10} {:>12?} {:>8.2} {:>12?} {:>8.2}",
+            script.len(),
+            tokens,
+            owned,
+            ns_per_byte(owned),
+            borrowed,
+            ns_per_byte(borrowed)
+        );
+    }
+}
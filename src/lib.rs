@@ -4,6 +4,40 @@ pub mod token {
     pub struct Token {
         pub kind: TokenType,
         pub literal: String,
+        pub span: Span,
+    }
+
+    // A token whose literal borrows from the source instead of owning a
+    // copy. For a STRING the literal is the raw text between the quotes,
+    // escapes and all; into_owned decodes it.
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct TokenRef<'src> {
+        pub kind: TokenType,
+        pub literal: &'src str,
+        pub span: Span,
+    }
+
+    impl<'src> TokenRef<'src> {
+        pub fn into_owned(self) -> Token {
+            let literal = match self.kind {
+                TokenType::STRING => {
+                    super::lexer::unescape(self.literal).unwrap_or_else(|| self.literal.to_string())
+                }
+                _ => self.literal.to_string(),
+            };
+
+            Token {
+                kind: self.kind,
+                literal,
+                span: self.span,
+            }
+        }
+    }
+
+    impl<'src> From<TokenRef<'src>> for Token {
+        fn from(token: TokenRef<'src>) -> Token {
+            token.into_owned()
+        }
     }
 
     #[derive(Debug, PartialEq, Clone)]
@@ -11,13 +45,19 @@ pub mod token {
         ILLEGAL,
         EOF,
         IDENT,     // add, foobar, x, y
-        INT,       // 12355
+        INT,       // 12355, 0xff, 1_000
+        FLOAT,     // 3.14, 1e-9
+        STRING,    // "foo bar"
+        COMMENT,   // // note, # note or /* note */
         COMMA,     // ,
         SEMICOLON, // ;
         LPAREN,    // (
         RPAREN,    // )
         LBRACE,    // {
         RBRACE,    // }
+        LBRACKET,  // [
+        RBRACKET,  // ]
+        COLON,     // :
 
         // Operators
         ASSIGN,   // =
@@ -26,10 +66,16 @@ pub mod token {
         BANG,     // !
         ASTERISK, // *
         SLASH,    // /
+        PERCENT,  // %
+        POWER,    // **
         LT,       // <
         GT,       // >
+        LTE,      // <=
+        GTE,      // >=
         EQ,       // ==
         NEQ,      // !=
+        AND,      // &&
+        OR,       // ||
 
         // Keywords
         FUNCTION, // fn
@@ -41,6 +87,85 @@ pub mod token {
         RETURN,   // return
     }
 
+    // Where a piece of source text sits in its input. Offsets are in
+    // bytes, so `&input[span.start..span.end]` is the text itself.
+    #[derive(Debug, PartialEq, Clone, Copy, Default)]
+    pub struct Span {
+        pub start: usize,  // byte offset of the first char
+        pub end: usize,    // byte offset just past the last char
+        pub line: usize,   // 1-based line of the first char
+        pub column: usize, // 1-based column of the first char, counted in chars
+    }
+
+    impl Span {
+        // The smallest span covering both self and other
+        pub fn to(self, other: Span) -> Span {
+            if other.start < self.start {
+                return other.to(self);
+            }
+            Span {
+                start: self.start,
+                end: self.end.max(other.end),
+                line: self.line,
+                column: self.column,
+            }
+        }
+    }
+
+    impl std::fmt::Display for Span {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "{}:{}", self.line, self.column)
+        }
+    }
+
+    impl TokenType {
+        // The literal every token of this kind is spelled with,
+        // empty for kinds like IDENT and INT whose literal varies
+        pub fn literal(&self) -> &'static str {
+            match self {
+                TokenType::ILLEGAL
+                | TokenType::EOF
+                | TokenType::IDENT
+                | TokenType::INT
+                | TokenType::FLOAT
+                | TokenType::STRING
+                | TokenType::COMMENT => "",
+                TokenType::COMMA => ",",
+                TokenType::SEMICOLON => ";",
+                TokenType::LPAREN => "(",
+                TokenType::RPAREN => ")",
+                TokenType::LBRACE => "{",
+                TokenType::RBRACE => "}",
+                TokenType::LBRACKET => "[",
+                TokenType::RBRACKET => "]",
+                TokenType::COLON => ":",
+                TokenType::ASSIGN => "=",
+                TokenType::PLUS => "+",
+                TokenType::MINUS => "-",
+                TokenType::BANG => "!",
+                TokenType::ASTERISK => "*",
+                TokenType::SLASH => "/",
+                TokenType::LT => "<",
+                TokenType::GT => ">",
+                TokenType::PERCENT => "%",
+                TokenType::POWER => "**",
+                TokenType::LTE => "<=",
+                TokenType::GTE => ">=",
+                TokenType::EQ => "==",
+                TokenType::NEQ => "!=",
+                TokenType::AND => "&&",
+                TokenType::OR => "||",
+                TokenType::FUNCTION => "fn",
+                TokenType::LET => "let",
+                TokenType::TRUE => "true",
+                TokenType::FALSE => "false",
+                TokenType::IF => "if",
+                TokenType::ELSE => "else",
+                TokenType::RETURN => "return",
+            }
+        }
+    }
+
     // Since it's really hard to initialize a static hashmap without
     // the use of a crate, this is good stopgap
     pub fn keyword(ident: &str) -> TokenType {
@@ -59,76 +184,255 @@ pub mod token {
 
 pub mod lexer {
     use super::token::*;
-    pub struct Lexer {
-        pub input: String,
+    // Positions are byte offsets into input, always on a char boundary,
+    // so moving to the next char and slicing out a token are both O(1).
+    // The input can be owned (a String) or borrowed (a &str); only a
+    // borrowing lexer can hand out tokens that borrow from the source.
+    pub struct Lexer<S = String> {
+        pub input: S,
         pub pos: usize,      // current position in input (points to current char)
         pub read_pos: usize, // current read post (after current char)
-        pub ch: char,        // current char under examination
+        pub ch: char,        // current char under examination, '\0' past the end
+        pub line: usize,     // line of the current char
+        pub column: usize,   // column of the current char
+        finished: bool,      // whether the iterator has handed out EOF
+        keep_comments: bool, // whether comments come out as COMMENT tokens
+        diagnostics: Vec<LexError>,
+        illegal: Option<LexErrorKind>, // why the token being read is ILLEGAL
     }
 
-    impl Lexer {
-        pub fn new(input: String) -> Lexer {
-            assert!(input.len() > 0, "Source code should not be empty");
+    impl<S: AsRef<str>> Lexer<S> {
+        // Any string is a valid source; an empty one lexes to just EOF
+        pub fn new(input: S) -> Lexer<S> {
             let mut l = Lexer {
                 input,
                 pos: 0,
                 read_pos: 0,
                 ch: '\0',
+                line: 1,
+                column: 0,
+                finished: false,
+                keep_comments: false,
+                diagnostics: Vec::new(),
+                illegal: None,
             };
             l.read_char();
             l
         }
 
+        // Starts over on a new input, as if freshly made with new
+        pub fn reset(&mut self, input: S) {
+            let keep_comments = self.keep_comments;
+            *self = Lexer::new(input).keep_comments(keep_comments);
+        }
+
+        // Comments are skipped like whitespace unless asked for, so tools
+        // that need to preserve them can get them as COMMENT tokens
+        pub fn keep_comments(mut self, keep: bool) -> Lexer<S> {
+            self.keep_comments = keep;
+            self
+        }
+
+        // Everything wrong with the input found so far. Each ILLEGAL token
+        // has at least one diagnostic explaining it.
+        pub fn diagnostics(&self) -> &[LexError] {
+            &self.diagnostics
+        }
+
+        fn src(&self) -> &str {
+            self.input.as_ref()
+        }
+
         // Reads a single character from the input stream
         // by advancing the position and read position
         pub fn read_char(&mut self) {
-            if self.read_pos >= self.input.len() {
-                self.ch = '\0';
+            if self.ch == '\n' {
+                self.line += 1;
+                self.column = 1;
             } else {
-                self.ch = self.input.chars().nth(self.read_pos).unwrap();
+                self.column += 1;
             }
+
             self.pos = self.read_pos;
-            self.read_pos += 1;
+            match self.src()[self.read_pos..].chars().next() {
+                Some(c) => {
+                    self.ch = c;
+                    self.read_pos += c.len_utf8();
+                }
+                None => self.ch = '\0',
+            }
+        }
+
+        // Whether the whole input has been consumed. A '\0' in ch on
+        // its own doesn't say, since the input itself may contain one.
+        pub fn at_end(&self) -> bool {
+            self.pos >= self.src().len()
         }
 
-        // If the character is detected as being alphabetic,
-        // all subsequent alphabetic characters are collected
-        // since this would be an identifier
-        pub fn read_ident(&mut self) -> String {
+        // If the character can start an identifier, all subsequent
+        // characters that can continue one are collected
+        pub fn read_ident(&mut self) -> &str {
             let start_pos = self.pos;
 
-            // Allows for names like foo_bar
-            while self.ch.is_alphabetic() || self.ch == '_' {
+            // Allows for names like foo_bar, x1 and café
+            while is_ident_continue(self.ch) {
                 self.read_char();
             }
 
             // return the ident range
-            self.input.get(start_pos..self.pos).unwrap().to_string()
+            &self.src()[start_pos..self.pos]
+        }
+
+        // Reads a double quoted string and leaves the lexer on the closing
+        // quote. Escapes are only checked here, not decoded, so nothing is
+        // allocated; the kind is ILLEGAL if the string is never closed or
+        // holds an invalid escape, and each bad escape gets a diagnostic.
+        pub fn read_string(&mut self) -> TokenType {
+            let mut valid = true;
+
+            loop {
+                self.read_char();
+                match self.ch {
+                    '"' => break,
+                    _ if self.at_end() => return self.illegal(LexErrorKind::UnterminatedString),
+                    '\\' => {
+                        let (start, line, column) = (self.pos, self.line, self.column);
+                        self.read_char();
+                        if self.at_end() {
+                            return self.illegal(LexErrorKind::UnterminatedString);
+                        }
+
+                        // On success, move onto the last char of the escape
+                        match read_escape(&self.src()[self.pos..]) {
+                            Some((_, len)) => {
+                                let end = self.pos + len;
+                                while self.read_pos < end {
+                                    self.read_char();
+                                }
+                            }
+                            None => {
+                                valid = false;
+                                let escape = self.src()[start..self.read_pos].to_string();
+                                self.diagnostics.push(LexError {
+                                    kind: LexErrorKind::InvalidEscape(escape),
+                                    span: Span {
+                                        start,
+                                        end: self.read_pos,
+                                        line,
+                                        column,
+                                    },
+                                });
+                            }
+                        }
+                    }
+                    _ => {}
+                }
+            }
+
+            if valid {
+                TokenType::STRING
+            } else {
+                TokenType::ILLEGAL
+            }
+        }
+
+        // Reads a comment running to the end of the line, leaving the lexer
+        // on its last char so the newline is left for skip_whtspc
+        pub fn read_line_comment(&mut self) -> TokenType {
+            while self.peek_char() != '\n' && self.read_pos < self.src().len() {
+                self.read_char();
+            }
+            TokenType::COMMENT
+        }
+
+        // Reads a block comment, with the lexer sitting on its opening `/`.
+        // Block comments nest, so each `/*` needs its own `*/`. Leaves the
+        // lexer on the final `/`, and the kind is ILLEGAL if the input ends
+        // before every comment is closed.
+        pub fn read_block_comment(&mut self) -> TokenType {
+            self.read_char();
+            let mut depth = 1;
+
+            while depth > 0 {
+                self.read_char();
+                if self.at_end() {
+                    return self.illegal(LexErrorKind::UnterminatedComment);
+                }
+
+                match (self.ch, self.peek_char()) {
+                    ('/', '*') => depth += 1,
+                    ('*', '/') => depth -= 1,
+                    _ => continue,
+                }
+                self.read_char();
+            }
+            TokenType::COMMENT
         }
 
-        // If the character is detected as being numeric,
-        // all subsequent numeric characters are collected
-        // since this would be an identifier
-        pub fn read_digit(&mut self) -> String {
+        // Reads a number: a decimal with an optional fraction and exponent,
+        // or an integer with a 0x, 0o or 0b prefix, where `_` may separate
+        // digits. Letters and digits running straight on are read too, so
+        // `0b102` or `9lives` is one ILLEGAL token rather than two tokens
+        // that happen to parse.
+        pub fn read_number(&mut self) -> TokenType {
             let start_pos = self.pos;
+            let radix = match (self.ch, self.peek_char()) {
+                ('0', 'x') | ('0', 'X') => 16,
+                ('0', 'o') | ('0', 'O') => 8,
+                ('0', 'b') | ('0', 'B') => 2,
+                _ => 10,
+            };
+            let mut kind = TokenType::INT;
+
+            if radix == 10 {
+                self.read_decimal_digits();
+                if self.ch == '.' && self.peek_char().is_ascii_digit() {
+                    kind = TokenType::FLOAT;
+                    self.read_char();
+                    self.read_decimal_digits();
+                }
+                if matches!(self.ch, 'e' | 'E') && self.exponent_follows() {
+                    kind = TokenType::FLOAT;
+                    self.read_char();
+                    if matches!(self.ch, '+' | '-') {
+                        self.read_char();
+                    }
+                    self.read_decimal_digits();
+                }
+            }
 
-            while self.ch.is_digit(10) {
+            while is_ident_continue(self.ch) {
                 self.read_char();
             }
 
-            // return the ident range
-            self.input.get(start_pos..self.pos).unwrap().to_string()
+            let text = &self.src()[start_pos..self.pos];
+            if is_valid_number(text, radix) {
+                kind
+            } else {
+                let text = text.to_string();
+                self.illegal(LexErrorKind::MalformedNumber(text))
+            }
+        }
+
+        fn read_decimal_digits(&mut self) {
+            while self.ch.is_ascii_digit() || self.ch == '_' {
+                self.read_char();
+            }
+        }
+
+        // Whether the `e` under the lexer starts an exponent, i.e. is
+        // followed by digits with an optional sign in between
+        fn exponent_follows(&self) -> bool {
+            let rest = &self.src()[self.read_pos..];
+            let rest = rest.strip_prefix(['+', '-']).unwrap_or(rest);
+            rest.starts_with(|c: char| c.is_ascii_digit())
         }
 
         // Peeks ahead in the input and returns that char
         // if the read_pos is greater than or equal to the input
         // return the EOF character
         pub fn peek_char(&self) -> char {
-            if self.read_pos >= self.input.len() {
-                '\0'
-            } else {
-                self.input.chars().nth(self.read_pos).unwrap()
-            }
+            self.src()[self.read_pos..].chars().next().unwrap_or('\0')
         }
 
         // Consumes all unicode whitespaces
@@ -139,235 +443,4595 @@ pub mod lexer {
         }
 
         pub fn next_token(&mut self) -> Token {
-            self.skip_whtspc();
-            let tok: Token = match self.ch {
-                '=' => {
-                    if self.peek_char() == '=' {
-                        self.read_char();
-                        Token {
-                            kind: TokenType::EQ,
-                            literal: "==".to_string(),
-                        }
-                    } else {
-                        Token {
-                            kind: TokenType::ASSIGN,
-                            literal: "=".to_string(),
-                        }
-                    }
+            let (kind, span) = self.scan();
+            let literal = literal(self.src(), &kind, span);
+            TokenRef {
+                kind,
+                literal,
+                span,
+            }
+            .into_owned()
+        }
+
+        // Finds the kind and extent of the next token without copying
+        // any of its text
+        fn scan(&mut self) -> (TokenType, Span) {
+            loop {
+                self.skip_whtspc();
+
+                let start = self.pos;
+                let (line, column) = (self.line, self.column);
+                let kind = self.read_token();
+                if kind == TokenType::COMMENT && !self.keep_comments {
+                    continue;
                 }
-                '+' => Token {
-                    kind: TokenType::PLUS,
-                    literal: "+".to_string(),
-                },
-                '-' => Token {
-                    kind: TokenType::MINUS,
-                    literal: "-".to_string(),
-                },
-                '!' => {
-                    if self.peek_char() == '=' {
-                        self.read_char();
-                        Token {
-                            kind: TokenType::NEQ,
-                            literal: "!=".to_string(),
-                        }
-                    } else {
-                        Token {
-                            kind: TokenType::BANG,
-                            literal: "!".to_string(),
-                        }
-                    }
+
+                let span = Span {
+                    start,
+                    end: self.pos,
+                    line,
+                    column,
+                };
+                if let Some(kind) = self.illegal.take() {
+                    self.diagnostics.push(LexError { kind, span });
                 }
-                '/' => Token {
-                    kind: TokenType::SLASH,
-                    literal: "/".to_string(),
-                },
-                '*' => Token {
-                    kind: TokenType::ASTERISK,
-                    literal: "*".to_string(),
-                },
-                '<' => Token {
-                    kind: TokenType::LT,
-                    literal: "<".to_string(),
-                },
-                '>' => Token {
-                    kind: TokenType::GT,
-                    literal: ">".to_string(),
-                },
-                ';' => Token {
-                    kind: TokenType::SEMICOLON,
-                    literal: ";".to_string(),
-                },
-                ',' => Token {
-                    kind: TokenType::COMMA,
-                    literal: ",".to_string(),
-                },
-                '(' => Token {
-                    kind: TokenType::LPAREN,
-                    literal: "(".to_string(),
-                },
-                ')' => Token {
-                    kind: TokenType::RPAREN,
-                    literal: ")".to_string(),
-                },
-                '{' => Token {
-                    kind: TokenType::LBRACE,
-                    literal: "{".to_string(),
-                },
-                '}' => Token {
-                    kind: TokenType::RBRACE,
-                    literal: "}".to_string(),
-                },
-                '\0' => Token {
-                    kind: TokenType::EOF,
-                    literal: "".to_string(),
+                return (kind, span);
+            }
+        }
+
+        // The two char token if the next char is second, moving onto
+        // it, or else the one char token for the current char alone
+        fn two_char_token(&mut self, second: char, two: TokenType, one: TokenType) -> TokenType {
+            if self.peek_char() == second {
+                self.read_char();
+                two
+            } else if one == TokenType::ILLEGAL {
+                self.illegal(LexErrorKind::UnexpectedChar(self.ch))
+            } else {
+                one
+            }
+        }
+
+        // Notes why the token being read is ILLEGAL, for scan to report
+        // once the token's span is known
+        fn illegal(&mut self, kind: LexErrorKind) -> TokenType {
+            self.illegal = Some(kind);
+            TokenType::ILLEGAL
+        }
+
+        // Reads the kind of the token starting at the current char,
+        // leaving the lexer on the first char after it
+        fn read_token(&mut self) -> TokenType {
+            let kind = match self.ch {
+                '=' => self.two_char_token('=', TokenType::EQ, TokenType::ASSIGN),
+                '+' => TokenType::PLUS,
+                '-' => TokenType::MINUS,
+                '!' => self.two_char_token('=', TokenType::NEQ, TokenType::BANG),
+                '&' => self.two_char_token('&', TokenType::AND, TokenType::ILLEGAL),
+                '|' => self.two_char_token('|', TokenType::OR, TokenType::ILLEGAL),
+                '%' => TokenType::PERCENT,
+                '/' => match self.peek_char() {
+                    '/' => self.read_line_comment(),
+                    '*' => self.read_block_comment(),
+                    _ => TokenType::SLASH,
                 },
+                '#' => self.read_line_comment(),
+                '*' => self.two_char_token('*', TokenType::POWER, TokenType::ASTERISK),
+                '<' => self.two_char_token('=', TokenType::LTE, TokenType::LT),
+                '>' => self.two_char_token('=', TokenType::GTE, TokenType::GT),
+                ';' => TokenType::SEMICOLON,
+                ',' => TokenType::COMMA,
+                ':' => TokenType::COLON,
+                '(' => TokenType::LPAREN,
+                ')' => TokenType::RPAREN,
+                '{' => TokenType::LBRACE,
+                '}' => TokenType::RBRACE,
+                '[' => TokenType::LBRACKET,
+                ']' => TokenType::RBRACKET,
+                '"' => self.read_string(),
+                '\0' if self.at_end() => TokenType::EOF,
                 c => {
-                    if c.is_alphabetic() {
-                        let literal = self.read_ident();
-                        let kind = keyword(literal.as_str());
-                        return Token { kind, literal };
-                    } else if c.is_digit(10) {
-                        let literal = self.read_digit();
-                        let kind = TokenType::INT;
-                        return Token { kind, literal };
+                    if is_ident_start(c) {
+                        return keyword(self.read_ident());
+                    } else if c.is_ascii_digit() {
+                        return self.read_number();
                     } else {
-                        Token {
-                            kind: TokenType::ILLEGAL,
-                            literal: c.to_string(),
-                        }
+                        self.illegal(LexErrorKind::UnexpectedChar(c))
                     }
                 }
             };
 
             self.read_char();
-            tok
+            kind
         }
     }
-}
 
-pub mod repl {
-    use super::lexer::*;
-    use super::token::*;
-    use std::error::Error;
-    use std::io::{self, Write};
-    pub struct Repl {
-        pub prompt: String,
+    // Yields every token up to and including EOF, then stops. Calling
+    // next_token directly keeps returning EOF instead.
+    impl<S: AsRef<str>> Iterator for Lexer<S> {
+        type Item = Token;
+
+        fn next(&mut self) -> Option<Token> {
+            if self.finished {
+                return None;
+            }
+            let tok = self.next_token();
+            self.finished = tok.kind == TokenType::EOF;
+            Some(tok)
+        }
     }
 
-    impl Repl {
-        pub fn new(prompt: String) -> Repl {
-            Repl { prompt }
+    impl<S: AsRef<str>> std::iter::FusedIterator for Lexer<S> {}
+
+    impl<'src> Lexer<&'src str> {
+        // Lexes raw bytes, which must be UTF-8 to be a source at all
+        pub fn from_bytes(input: &'src [u8]) -> Result<Lexer<&'src str>, InvalidSource> {
+            Ok(Lexer::new(source_from_bytes(input)?))
         }
+    }
 
-        pub fn run(&self) -> Result<(), Box<dyn Error>> {
-            println!("Monkey Lang v0.1 - REPL");
+    impl Lexer<String> {
+        // Like from_bytes, but takes ownership of the bytes
+        pub fn from_utf8(input: Vec<u8>) -> Result<Lexer<String>, InvalidSource> {
+            String::from_utf8(input)
+                .map(Lexer::new)
+                .map_err(|err| invalid_source(err.as_bytes(), err.utf8_error()))
+        }
+    }
 
-            loop {
-                let mut input = String::new();
-                let mut lexer = Lexer::new(String::from("something"));
-                print!("{} ", self.prompt);
-                io::stdout().flush()?;
-                io::stdin().read_line(&mut input)?;
-
-                lexer.input = input.clone();
-                loop {
-                    let tok = lexer.next_token();
-                    match tok.kind {
-                        TokenType::EOF => break,
-                        TokenType::IDENT | TokenType::INT => {
-                            println!("{:#?}({:#?})", tok.kind, tok.literal);
-                        }
-                        _ => {
-                            println!("{:#?}", tok.kind);
-                        }
-                    }
-                    io::stdout().flush()?;
+    #[derive(Debug, PartialEq, Clone)]
+    pub enum LexErrorKind {
+        UnexpectedChar(char),    // a char no token starts with
+        InvalidEscape(String),   // a backslash escape in a string that means nothing
+        UnterminatedString,      // the input ends inside a string
+        UnterminatedComment,     // the input ends inside a block comment
+        MalformedNumber(String), // a number with bad digits, separators or suffix
+    }
+
+    // Something wrong with the input that the lexer noticed while
+    // reading it, and where it sits in the source
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct LexError {
+        pub kind: LexErrorKind,
+        pub span: Span,
+    }
+
+    impl LexError {
+        // What went wrong, without the location
+        pub fn message(&self) -> String {
+            match &self.kind {
+                LexErrorKind::UnexpectedChar(c) => format!("unexpected character {:?}", c),
+                LexErrorKind::InvalidEscape(escape) => {
+                    format!("invalid escape sequence `{}` in string", escape)
                 }
+                LexErrorKind::UnterminatedString => "unterminated string".to_string(),
+                LexErrorKind::UnterminatedComment => "unterminated block comment".to_string(),
+                LexErrorKind::MalformedNumber(text) => format!("malformed number `{}`", text),
             }
-            Ok(())
         }
     }
-}
 
-pub mod ast {
-    use super::token::*;
-    pub trait Node {
-        fn token_literal(&self) -> String;
+    impl std::fmt::Display for LexError {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "{}: {}", self.span, self.message())
+        }
     }
 
-    pub trait Statement: Node {}
+    impl std::error::Error for LexError {}
 
-    pub trait Expression: Node {}
+    // Lexes a whole source just to find what's wrong with it
+    pub fn diagnose(input: &str) -> Vec<LexError> {
+        let mut lexer = Lexer::new(input);
+        while lexer.next_token_ref().kind != TokenType::EOF {}
+        lexer.diagnostics
+    }
 
-    pub struct LetStmt<'a> {
-        token: Token,          // the Token::LET token
-        name: &'a Identifier,  // holds the identifier of the binding
-        value: dyn Expression, // the expression that produces a value
+    // Input that can't be lexed at all, as opposed to a source that
+    // merely holds ILLEGAL tokens. The span covers the first bad bytes.
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct InvalidSource {
+        pub span: Span,
     }
 
-    impl<'a> Node for LetStmt<'a> {
-        fn token_literal(&self) -> String {
-            self.token.literal.clone()
+    impl std::fmt::Display for InvalidSource {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "{}: source is not valid UTF-8", self.span)
         }
     }
 
-    impl<'a> Statement for LetStmt<'a> {}
+    impl std::error::Error for InvalidSource {}
 
-    pub struct Identifier {
-        token: Token,
-        value: String,
+    // Checks that raw bytes are UTF-8 and so can be lexed
+    pub fn source_from_bytes(input: &[u8]) -> Result<&str, InvalidSource> {
+        std::str::from_utf8(input).map_err(|err| invalid_source(input, err))
     }
 
-    impl Node for Identifier {
-        fn token_literal(&self) -> String {
-            self.token.literal.clone()
+    // Points at the bytes where UTF-8 decoding of input failed
+    fn invalid_source(input: &[u8], err: std::str::Utf8Error) -> InvalidSource {
+        let start = err.valid_up_to();
+        let valid = std::str::from_utf8(&input[..start]).unwrap_or_default();
+        let line_start = valid.rfind('\n').map_or(0, |i| i + 1);
+
+        InvalidSource {
+            span: Span {
+                start,
+                end: start + err.error_len().unwrap_or(input.len() - start),
+                line: valid.matches('\n').count() + 1,
+                column: valid[line_start..].chars().count() + 1,
+            },
         }
     }
 
-    impl Expression for Identifier {}
-
-    // Program is going to be the root node of every AST the Parser produces
-    pub struct Program {
-        statements: Vec<Box<dyn Statement>>,
+    // Lexes a whole source string, ending with its EOF token
+    pub fn tokenize(input: &str) -> Vec<Token> {
+        Lexer::new(input).collect()
     }
 
-    impl Node for Program {
-        fn token_literal(&self) -> String {
-            if self.statements.len() > 0 {
-                self.statements.get(0).unwrap().token_literal()
-            } else {
-                "".to_string()
+    impl<'src> Lexer<&'src str> {
+        // Like next_token, but the literal is a slice of the input
+        // rather than a copy, so lexing doesn't allocate at all
+        pub fn next_token_ref(&mut self) -> TokenRef<'src> {
+            let (kind, span) = self.scan();
+            let input: &'src str = self.input;
+            TokenRef {
+                literal: literal(input, &kind, span),
+                kind,
+                span,
             }
         }
     }
+
+    // Identifiers follow the shape of Unicode's XID_Start/XID_Continue:
+    // a letter or `_` first, then letters, digits, `_` or combining marks.
+    // Without the Unicode tables to hand, letters and digits are whatever
+    // std considers alphabetic and numeric.
+    pub fn is_ident_start(c: char) -> bool {
+        c.is_alphabetic() || c == '_'
+    }
+
+    pub fn is_ident_continue(c: char) -> bool {
+        c.is_alphanumeric() || c == '_' || is_combining_mark(c)
+    }
+
+    // The combining diacritical mark blocks, so a name written with
+    // decomposed accents (e.g. `e` + U+0301) stays one identifier
+    fn is_combining_mark(c: char) -> bool {
+        matches!(
+            c,
+            '\u{0300}'..='\u{036F}'
+                | '\u{1AB0}'..='\u{1AFF}'
+                | '\u{1DC0}'..='\u{1DFF}'
+                | '\u{20D0}'..='\u{20FF}'
+                | '\u{FE20}'..='\u{FE2F}'
+        )
+    }
+
+    // Checks the text of a number as read by read_number. Underscores
+    // are only allowed between two digits.
+    fn is_valid_number(text: &str, radix: u32) -> bool {
+        if radix != 10 {
+            return is_valid_digits(&text[2..], radix);
+        }
+
+        let (mantissa, exponent) = match text.find(['e', 'E']) {
+            Some(i) => (&text[..i], Some(&text[i + 1..])),
+            None => (text, None),
+        };
+        let exponent_valid = exponent.is_none_or(|exponent| {
+            let digits = exponent.strip_prefix(['+', '-']).unwrap_or(exponent);
+            is_valid_digits(digits, 10)
+        });
+
+        exponent_valid && mantissa.split('.').all(|part| is_valid_digits(part, 10))
+    }
+
+    fn is_valid_digits(digits: &str, radix: u32) -> bool {
+        !digits.is_empty()
+            && !digits.starts_with('_')
+            && !digits.ends_with('_')
+            && !digits.contains("__")
+            && digits.chars().all(|c| c == '_' || c.is_digit(radix))
+    }
+
+    // A token's literal is its text in the source, except that a STRING
+    // leaves out its quotes
+    fn literal<'a>(input: &'a str, kind: &TokenType, span: Span) -> &'a str {
+        match kind {
+            TokenType::STRING => &input[span.start + 1..span.end - 1],
+            _ => &input[span.start..span.end],
+        }
+    }
+
+    // Decodes the escape sequences in the raw text of a string literal,
+    // or returns None if one of them is invalid
+    pub fn unescape(raw: &str) -> Option<String> {
+        let mut value = String::with_capacity(raw.len());
+        let mut rest = raw;
+
+        while let Some(i) = rest.find('\\') {
+            value.push_str(&rest[..i]);
+            let (c, len) = read_escape(&rest[i + 1..])?;
+            value.push(c);
+            rest = &rest[i + 1 + len..];
+        }
+
+        value.push_str(rest);
+        Some(value)
+    }
+
+    // Reads one escape from the text just after a backslash, giving the
+    // char it stands for and how many bytes it took up
+    fn read_escape(rest: &str) -> Option<(char, usize)> {
+        let c = match rest.chars().next()? {
+            'n' => '\n',
+            't' => '\t',
+            'r' => '\r',
+            '"' => '"',
+            '\\' => '\\',
+            'u' => return read_unicode_escape(rest),
+            _ => return None,
+        };
+        Some((c, 1))
+    }
+
+    // Reads a `u{XXXX}` escape. Stops early at a closing quote so a
+    // broken escape can't swallow the end of the string.
+    fn read_unicode_escape(rest: &str) -> Option<(char, usize)> {
+        let body = rest.strip_prefix("u{")?;
+        let close = body.find(['}', '"'])?;
+        let digits = &body[..close];
+
+        if !body[close..].starts_with('}') || digits.is_empty() || digits.len() > 6 {
+            return None;
+        }
+        let c = u32::from_str_radix(digits, 16)
+            .ok()
+            .and_then(std::char::from_u32)?;
+        Some((c, 2 + close + 1))
+    }
 }
 
-pub mod parser {
-    use super::{ast::*, lexer::*, token::*};
+pub mod repl {
+    use super::ast::*;
+    use super::compiler::{Compiler, SymbolTable};
+    use super::eval::*;
+    use super::lexer::*;
+    use super::object::*;
+    use super::parser;
+    use super::script::Engine;
+    use super::token::*;
+    use super::vm::Vm;
+    use std::cell::RefCell;
+    use std::error::Error;
+    use std::io::{self, BufRead, Write};
+    use std::rc::Rc;
 
-    pub struct Parser<'a> {
-        lexer: &'a mut Lexer,
+    pub struct Repl {
+        pub prompt: String,
+        pub engine: Engine,
+        pub color: bool,
+    }
 
-        cur_token: Token,
-        peek_token: Token,
+    // What a session remembers from one line to the next
+    enum Session {
+        Eval(Rc<RefCell<Environment>>),
+        Vm {
+            symbol_table: SymbolTable,
+            constants: Vec<Object>,
+            globals: Vec<Option<Object>>,
+        },
     }
 
-    impl<'a> Parser<'a> {
-        fn new(lexer: &mut Lexer) -> Parser {
-            let cur_token = lexer.next_token();
-            let peek_token = lexer.next_token();
-            Parser {
-                lexer,
-                cur_token,
-                peek_token,
+    impl Repl {
+        pub fn new(prompt: String) -> Repl {
+            Repl {
+                prompt,
+                engine: Engine::Eval,
+                color: false,
             }
         }
 
-        fn next_token(&mut self) {
-            self.cur_token = self.peek_token.clone();
-            self.peek_token = self.lexer.next_token();
+        pub fn engine(mut self, engine: Engine) -> Self {
+            self.engine = engine;
+            self
+        }
+
+        // Shows errors in red
+        pub fn color(mut self, color: bool) -> Self {
+            self.color = color;
+            self
+        }
+
+        pub fn run(&self) -> Result<(), Box<dyn Error>> {
+            let stdin = io::stdin();
+            self.run_with(stdin.lock(), io::stdout())
+        }
+
+        // Reads lines from input until it runs dry, evaluating each one
+        // against the same environment so bindings carry over between lines
+        pub fn run_with<R: BufRead, W: Write>(
+            &self,
+            mut input: R,
+            mut output: W,
+        ) -> Result<(), Box<dyn Error>> {
+            writeln!(output, "Monkey Lang v0.1 - REPL")?;
+            let mut session = match self.engine {
+                Engine::Eval => Session::Eval(Rc::new(RefCell::new(Environment::new()))),
+                Engine::Vm => {
+                    let (symbol_table, constants) = Compiler::new().into_state();
+                    Session::Vm {
+                        symbol_table,
+                        constants,
+                        globals: Vec::new(),
+                    }
+                }
+            };
+
+            loop {
+                let mut bytes = Vec::new();
+                write!(output, "{} ", self.prompt)?;
+                output.flush()?;
+                if input.read_until(b'\n', &mut bytes)? == 0 {
+                    // stdin was closed (e.g. Ctrl-D)
+                    break;
+                }
+
+                let line = match source_from_bytes(&bytes) {
+                    Ok(line) => line,
+                    Err(err) => {
+                        self.print_error(&mut output, &err.to_string())?;
+                        continue;
+                    }
+                };
+
+                match line.trim_start().strip_prefix(":tokens") {
+                    Some(source) => print_tokens(source, &mut output)?,
+                    None => self.eval_line(line, &mut session, &mut output)?,
+                }
+                output.flush()?;
+            }
+            Ok(())
         }
 
-        fn parse_program() {}
+        fn eval_line<W: Write>(
+            &self,
+            line: &str,
+            session: &mut Session,
+            output: &mut W,
+        ) -> io::Result<()> {
+            let mut lexer = Lexer::new(line.to_string());
+            let mut parser = parser::Parser::new(&mut lexer);
+            let program = parser.parse_program();
+            let errors = parser.errors().to_vec();
+            let diagnostics = lexer.diagnostics();
+
+            // What the lexer found comes first, since a bad token is
+            // usually what the parse errors after it trip over
+            if !diagnostics.is_empty() || !errors.is_empty() {
+                let mut message = String::from("Woops! We ran into some monkey business here!");
+                if !diagnostics.is_empty() {
+                    message.push_str("\n lexer errors:");
+                    for diagnostic in diagnostics.iter() {
+                        message.push_str(&format!("\n\t{}", diagnostic));
+                    }
+                }
+                if !errors.is_empty() {
+                    message.push_str("\n parser errors:");
+                    for error in errors.iter() {
+                        message.push_str(&format!("\n\t{}", error));
+                    }
+                }
+                return self.print_error(output, &message);
+            }
+
+            let value = match session {
+                Session::Eval(env) => eval_program(&program, env),
+                Session::Vm {
+                    symbol_table,
+                    constants,
+                    globals,
+                } => {
+                    // Works on copies, so a line that fails to compile leaves
+                    // no trace of the bindings it got through before failing
+                    let mut compiler =
+                        Compiler::new_with_state(symbol_table.clone(), constants.clone());
+                    match compiler.compile(&program) {
+                        Err(err) => Object::Error(RuntimeError::new(err.message)),
+                        Ok(()) => {
+                            let bytecode = compiler.bytecode();
+                            let (table, pool) = compiler.into_state();
+                            *symbol_table = table;
+                            *constants = pool;
+
+                            let mut vm = Vm::new_with_globals(bytecode, std::mem::take(globals));
+                            let result = vm.run();
+                            let value = vm.last_popped().clone();
+                            *globals = vm.into_globals();
+                            match result {
+                                Ok(()) => value,
+                                Err(err) => Object::Error(err),
+                            }
+                        }
+                    }
+                }
+            };
+
+            // A binding on its own has nothing worth showing
+            match program.statements.last() {
+                None | Some(Statement::Let { .. }) if !value.is_error() => Ok(()),
+                _ if value.is_error() => self.print_error(output, &value.to_string()),
+                _ => writeln!(output, "{}", value),
+            }
+        }
+
+        fn print_error<W: Write>(&self, output: &mut W, message: &str) -> io::Result<()> {
+            if self.color {
+                writeln!(output, "\x1b[31m{}\x1b[0m", message)
+            } else {
+                writeln!(output, "{}", message)
+            }
+        }
+    }
+
+    // Dumps the tokens of a line the way the REPL used to before it evaluated
+    // and then anything the lexer found wrong with it
+    pub fn print_tokens<W: Write>(source: &str, output: &mut W) -> io::Result<()> {
+        let mut lexer = Lexer::new(source);
+        for tok in lexer.by_ref() {
+            match tok.kind {
+                TokenType::EOF => {}
+                TokenType::IDENT | TokenType::INT | TokenType::FLOAT | TokenType::STRING => {
+                    writeln!(output, "{:#?}({:#?})", tok.kind, tok.literal)?;
+                }
+                _ => {
+                    writeln!(output, "{:#?}", tok.kind)?;
+                }
+            }
+        }
+        for diagnostic in lexer.diagnostics() {
+            writeln!(output, "error: {}", diagnostic)?;
+        }
+        Ok(())
+    }
+}
+
+pub mod ast {
+    use super::token::*;
+    use std::fmt;
+
+    pub trait Node {
+        fn token_literal(&self) -> String;
+    }
+
+    #[derive(Debug, PartialEq, Clone)]
+    pub enum Statement {
+        // let <name> = <value>;
+        Let { name: Identifier, value: Expression },
+        // return <value>;
+        Return(Expression),
+        // A statement consisting solely of one expression, e.g. `x + 10;`
+        Expression(Expression),
+    }
+
+    impl Node for Statement {
+        fn token_literal(&self) -> String {
+            match self {
+                Statement::Let { .. } => TokenType::LET.literal().to_string(),
+                Statement::Return(_) => TokenType::RETURN.literal().to_string(),
+                Statement::Expression(expr) => expr.token_literal(),
+            }
+        }
+    }
+
+    // { <statements> }
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct BlockStatement {
+        pub statements: Vec<Statement>,
+    }
+
+    impl Node for BlockStatement {
+        fn token_literal(&self) -> String {
+            TokenType::LBRACE.literal().to_string()
+        }
+    }
+
+    #[derive(Debug, Clone)]
+    pub struct Identifier {
+        pub value: String,
+        pub span: Span,
+    }
+
+    impl Identifier {
+        pub fn new(value: String, span: Span) -> Identifier {
+            Identifier { value, span }
+        }
+    }
+
+    // Compared by name alone, the same way expressions ignore their spans
+    impl PartialEq for Identifier {
+        fn eq(&self, other: &Identifier) -> bool {
+            self.value == other.value
+        }
+    }
+
+    impl Node for Identifier {
+        fn token_literal(&self) -> String {
+            self.value.clone()
+        }
+    }
+
+    // An expression along with the source it was parsed from
+    #[derive(Debug, Clone)]
+    pub struct Expression {
+        pub kind: ExpressionKind,
+        pub span: Span,
+    }
+
+    impl Expression {
+        pub fn new(kind: ExpressionKind, span: Span) -> Expression {
+            Expression { kind, span }
+        }
+    }
+
+    // Trees are compared by structure alone, so two parses of the same
+    // code compare equal however differently it was laid out
+    impl PartialEq for Expression {
+        fn eq(&self, other: &Expression) -> bool {
+            self.kind == other.kind
+        }
+    }
+
+    // Mostly useful for building trees by hand, where there's no source to point at
+    impl From<ExpressionKind> for Expression {
+        fn from(kind: ExpressionKind) -> Expression {
+            Expression::new(kind, Span::default())
+        }
+    }
+
+    #[derive(Debug, PartialEq, Clone)]
+    pub enum ExpressionKind {
+        Identifier(Identifier),
+        Integer(i64),
+        Float(f64),
+        Boolean(bool),
+        String(String),
+        // <operator><right>, e.g. `!ok` or `-5`
+        Prefix {
+            operator: TokenType,
+            right: Box<Expression>,
+        },
+        // <left> <operator> <right>, e.g. `5 * 5`
+        Infix {
+            left: Box<Expression>,
+            operator: TokenType,
+            right: Box<Expression>,
+        },
+        // if (<condition>) <consequence> else <alternative>
+        If {
+            condition: Box<Expression>,
+            consequence: BlockStatement,
+            alternative: Option<BlockStatement>,
+        },
+        // fn(<parameters>) <body>
+        Function {
+            parameters: Vec<Identifier>,
+            body: BlockStatement,
+        },
+        // <function>(<arguments>), where function is an
+        // Identifier or a Function literal
+        Call {
+            function: Box<Expression>,
+            arguments: Vec<Expression>,
+        },
+        // [<elements>]
+        Array(Vec<Expression>),
+        // <left>[<index>]
+        Index {
+            left: Box<Expression>,
+            index: Box<Expression>,
+        },
+        // {<key>: <value>, ...}, pairs kept in source order
+        Hash(Vec<(Expression, Expression)>),
+    }
+
+    impl Node for Expression {
+        fn token_literal(&self) -> String {
+            match &self.kind {
+                ExpressionKind::Identifier(ident) => ident.token_literal(),
+                ExpressionKind::Integer(value) => value.to_string(),
+                ExpressionKind::Float(value) => format!("{:?}", value),
+                ExpressionKind::Boolean(value) => value.to_string(),
+                ExpressionKind::String(value) => value.clone(),
+                ExpressionKind::Prefix { operator, .. }
+                | ExpressionKind::Infix { operator, .. } => operator.literal().to_string(),
+                ExpressionKind::If { .. } => TokenType::IF.literal().to_string(),
+                ExpressionKind::Function { .. } => TokenType::FUNCTION.literal().to_string(),
+                ExpressionKind::Call { .. } => TokenType::LPAREN.literal().to_string(),
+                ExpressionKind::Array(_) | ExpressionKind::Index { .. } => {
+                    TokenType::LBRACKET.literal().to_string()
+                }
+                ExpressionKind::Hash(_) => TokenType::LBRACE.literal().to_string(),
+            }
+        }
+    }
+
+    // Program is going to be the root node of every AST the Parser produces
+    #[derive(Debug, PartialEq, Clone, Default)]
+    pub struct Program {
+        pub statements: Vec<Statement>,
+    }
+
+    impl Node for Program {
+        fn token_literal(&self) -> String {
+            match self.statements.first() {
+                Some(stmt) => stmt.token_literal(),
+                None => "".to_string(),
+            }
+        }
+    }
+
+    // The Display impls below render the tree back into Monkey source.
+    // Every prefix and infix expression is wrapped in parentheses, so the
+    // output spells out exactly how the parser grouped the operators while
+    // still parsing back into the same tree.
+
+    impl fmt::Display for Program {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            for (i, stmt) in self.statements.iter().enumerate() {
+                if i > 0 {
+                    writeln!(f)?;
+                }
+                write!(f, "{}", stmt)?;
+            }
+            Ok(())
+        }
+    }
+
+    impl fmt::Display for Statement {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                Statement::Let { name, value } => write!(f, "let {} = {};", name, value),
+                Statement::Return(value) => write!(f, "return {};", value),
+                Statement::Expression(expr) => write!(f, "{};", expr),
+            }
+        }
+    }
+
+    impl fmt::Display for BlockStatement {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            if self.statements.is_empty() {
+                return write!(f, "{{}}");
+            }
+
+            write!(f, "{{")?;
+            for stmt in self.statements.iter() {
+                write!(f, " {}", stmt)?;
+            }
+            write!(f, " }}")
+        }
+    }
+
+    impl fmt::Display for Identifier {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{}", self.value)
+        }
+    }
+
+    // Writes items separated by ", "
+    fn write_list<T: fmt::Display>(f: &mut fmt::Formatter, items: &[T]) -> fmt::Result {
+        for (i, item) in items.iter().enumerate() {
+            if i > 0 {
+                write!(f, ", ")?;
+            }
+            write!(f, "{}", item)?;
+        }
+        Ok(())
+    }
+
+    // Writes a string literal, escaping whatever the lexer would
+    // otherwise read differently
+    fn write_quoted(f: &mut fmt::Formatter, value: &str) -> fmt::Result {
+        write!(f, "\"")?;
+        for c in value.chars() {
+            match c {
+                '"' => write!(f, "\\\"")?,
+                '\\' => write!(f, "\\\\")?,
+                '\n' => write!(f, "\\n")?,
+                '\t' => write!(f, "\\t")?,
+                '\r' => write!(f, "\\r")?,
+                c if c.is_control() => write!(f, "\\u{{{:x}}}", c as u32)?,
+                c => write!(f, "{}", c)?,
+            }
+        }
+        write!(f, "\"")
+    }
+
+    impl fmt::Display for Expression {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match &self.kind {
+                ExpressionKind::Identifier(ident) => write!(f, "{}", ident),
+                ExpressionKind::Integer(value) => write!(f, "{}", value),
+                // Debug keeps the `.0` on whole floats so they read back as floats
+                ExpressionKind::Float(value) => write!(f, "{:?}", value),
+                ExpressionKind::Boolean(value) => write!(f, "{}", value),
+                ExpressionKind::String(value) => write_quoted(f, value),
+                ExpressionKind::Prefix { operator, right } => {
+                    write!(f, "({}{})", operator.literal(), right)
+                }
+                ExpressionKind::Infix {
+                    left,
+                    operator,
+                    right,
+                } => write!(f, "({} {} {})", left, operator.literal(), right),
+                ExpressionKind::If {
+                    condition,
+                    consequence,
+                    alternative,
+                } => {
+                    write!(f, "if ({}) {}", condition, consequence)?;
+                    match alternative {
+                        Some(alternative) => write!(f, " else {}", alternative),
+                        None => Ok(()),
+                    }
+                }
+                ExpressionKind::Function { parameters, body } => {
+                    write!(f, "fn(")?;
+                    write_list(f, parameters)?;
+                    write!(f, ") {}", body)
+                }
+                ExpressionKind::Call {
+                    function,
+                    arguments,
+                } => {
+                    write!(f, "{}(", function)?;
+                    write_list(f, arguments)?;
+                    write!(f, ")")
+                }
+                ExpressionKind::Array(elements) => {
+                    write!(f, "[")?;
+                    write_list(f, elements)?;
+                    write!(f, "]")
+                }
+                ExpressionKind::Index { left, index } => write!(f, "({}[{}])", left, index),
+                ExpressionKind::Hash(pairs) => {
+                    write!(f, "{{")?;
+                    for (i, (key, value)) in pairs.iter().enumerate() {
+                        if i > 0 {
+                            write!(f, ", ")?;
+                        }
+                        write!(f, "{}: {}", key, value)?;
+                    }
+                    write!(f, "}}")
+                }
+            }
+        }
+    }
+}
+
+pub mod parser {
+    use super::{ast::*, lexer::*, token::*};
+    use std::collections::VecDeque;
+
+    // Binding power of each operator, from loosest to tightest
+    #[derive(Debug, PartialEq, PartialOrd, Clone, Copy)]
+    pub enum Precedence {
+        Lowest,
+        LogicalOr,   // ||
+        LogicalAnd,  // &&
+        Equals,      // ==
+        LessGreater, // > or <
+        Sum,         // +
+        Product,     // *
+        Prefix,      // -x or !x
+        Power,       // **
+        Call,        // myFunction(x)
+        Index,       // array[index]
+    }
+
+    pub fn precedence(kind: &TokenType) -> Precedence {
+        match kind {
+            TokenType::OR => Precedence::LogicalOr,
+            TokenType::AND => Precedence::LogicalAnd,
+            TokenType::EQ | TokenType::NEQ => Precedence::Equals,
+            TokenType::LT | TokenType::GT | TokenType::LTE | TokenType::GTE => {
+                Precedence::LessGreater
+            }
+            TokenType::PLUS | TokenType::MINUS => Precedence::Sum,
+            TokenType::ASTERISK | TokenType::SLASH | TokenType::PERCENT => Precedence::Product,
+            TokenType::POWER => Precedence::Power,
+            TokenType::LPAREN => Precedence::Call,
+            TokenType::LBRACKET => Precedence::Index,
+            _ => Precedence::Lowest,
+        }
+    }
+
+    #[derive(Debug, PartialEq, Clone)]
+    pub enum ParseErrorKind {
+        Expected(TokenType), // a specific token had to come next
+        ExpectedExpression,  // the token can't start an expression
+        InvalidInteger,      // an INT literal that can't be read as a number
+        IntegerOverflow,     // an INT literal that doesn't fit in an i64
+        InvalidFloat,        // a FLOAT literal that can't be read as a number
+        FloatOverflow,       // a FLOAT literal too large to be finite as an f64
+    }
+
+    // A syntax error along with the token that caused it
+    // and where that token sits in the source
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct ParseError {
+        pub kind: ParseErrorKind,
+        pub found: Token,
+        pub span: Span,
+    }
+
+    impl std::fmt::Display for ParseError {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "{}: ", self.span)?;
+            match &self.kind {
+                ParseErrorKind::Expected(kind) => write!(f, "expected {:?}", kind)?,
+                ParseErrorKind::ExpectedExpression => write!(f, "expected an expression")?,
+                ParseErrorKind::InvalidInteger => write!(f, "invalid integer literal")?,
+                ParseErrorKind::IntegerOverflow => {
+                    write!(f, "integer literal is larger than {}", i64::MAX)?
+                }
+                ParseErrorKind::InvalidFloat => write!(f, "invalid float literal")?,
+                ParseErrorKind::FloatOverflow => {
+                    write!(f, "float literal is larger than {:e}", f64::MAX)?
+                }
+            }
+            match self.found.kind {
+                TokenType::EOF => write!(f, ", found end of input"),
+                _ => write!(f, ", found {:?} {:?}", self.found.kind, self.found.literal),
+            }
+        }
+    }
+
+    impl std::error::Error for ParseError {}
+
+    // Parses a whole source string, handing back everything that could be
+    // parsed together with every error that was encountered along the way
+    pub fn parse(input: &str) -> (Program, Vec<ParseError>) {
+        let mut lexer = Lexer::new(input.to_string());
+        let mut parser = Parser::new(&mut lexer);
+        let program = parser.parse_program();
+        (program, parser.errors)
+    }
+
+    pub struct Parser<'a> {
+        lexer: &'a mut Lexer,
+        errors: Vec<ParseError>,
+
+        cur_token: Token,
+        peek_token: Token,
+        lookahead: VecDeque<Token>, // tokens already lexed beyond peek_token
+    }
+
+    impl<'a> Parser<'a> {
+        pub fn new(lexer: &'a mut Lexer) -> Parser<'a> {
+            let cur_token = lexer.next_token();
+            let peek_token = lexer.next_token();
+            Parser {
+                lexer,
+                errors: Vec::new(),
+                cur_token,
+                peek_token,
+                lookahead: VecDeque::new(),
+            }
+        }
+
+        // Every error the parser ran into while building the Program
+        pub fn errors(&self) -> &[ParseError] {
+            &self.errors
+        }
+
+        fn next_token(&mut self) {
+            let next = match self.lookahead.pop_front() {
+                Some(tok) => tok,
+                None => self.lexer.next_token(),
+            };
+            self.cur_token = std::mem::replace(&mut self.peek_token, next);
+        }
+
+        // Looks n tokens past the current one without consuming anything,
+        // so peek_nth(1) is the peek token. Past the end it's always EOF.
+        pub fn peek_nth(&mut self, n: usize) -> &Token {
+            match n {
+                0 => &self.cur_token,
+                1 => &self.peek_token,
+                _ => {
+                    while self.lookahead.len() < n - 1 {
+                        let tok = self.lexer.next_token();
+                        self.lookahead.push_back(tok);
+                    }
+                    &self.lookahead[n - 2]
+                }
+            }
+        }
+
+        fn cur_token_is(&self, kind: &TokenType) -> bool {
+            self.cur_token.kind == *kind
+        }
+
+        fn peek_token_is(&self, kind: &TokenType) -> bool {
+            self.peek_token.kind == *kind
+        }
+
+        fn cur_error(&mut self, kind: ParseErrorKind) {
+            self.errors.push(ParseError {
+                kind,
+                found: self.cur_token.clone(),
+                span: self.cur_token.span,
+            });
+        }
+
+        // Only advances if the next token is of the expected kind,
+        // otherwise an error is recorded and the token is left in place
+        fn expect_peek(&mut self, kind: TokenType) -> bool {
+            if self.peek_token_is(&kind) {
+                self.next_token();
+                true
+            } else {
+                self.errors.push(ParseError {
+                    kind: ParseErrorKind::Expected(kind),
+                    found: self.peek_token.clone(),
+                    span: self.peek_token.span,
+                });
+                false
+            }
+        }
+
+        // After a broken statement, skips ahead to where parsing can
+        // sensibly resume: just past the next top level semicolon, or
+        // on the closing brace of the block we're currently in
+        fn synchronize(&mut self) {
+            let mut depth = 0;
+            loop {
+                match self.cur_token.kind {
+                    TokenType::EOF => return,
+                    TokenType::SEMICOLON if depth == 0 => {
+                        self.next_token();
+                        return;
+                    }
+                    TokenType::RBRACE if depth == 0 => return,
+                    TokenType::RBRACE => depth -= 1,
+                    TokenType::LBRACE => depth += 1,
+                    _ => {}
+                }
+                self.next_token();
+            }
+        }
+
+        fn cur_precedence(&self) -> Precedence {
+            precedence(&self.cur_token.kind)
+        }
+
+        fn peek_precedence(&self) -> Precedence {
+            precedence(&self.peek_token.kind)
+        }
+
+        pub fn parse_program(&mut self) -> Program {
+            let mut statements = Vec::new();
+
+            while !self.cur_token_is(&TokenType::EOF) {
+                match self.parse_statement() {
+                    Some(stmt) => {
+                        statements.push(stmt);
+                        self.next_token();
+                    }
+                    None => {
+                        self.synchronize();
+                        // a stray closing brace can't end anything up here
+                        if self.cur_token_is(&TokenType::RBRACE) {
+                            self.next_token();
+                        }
+                    }
+                }
+            }
+
+            Program { statements }
+        }
+
+        fn parse_statement(&mut self) -> Option<Statement> {
+            match self.cur_token.kind {
+                TokenType::LET => self.parse_let_statement(),
+                TokenType::RETURN => self.parse_return_statement(),
+                _ => self.parse_expression_statement(),
+            }
+        }
+
+        fn parse_let_statement(&mut self) -> Option<Statement> {
+            if !self.expect_peek(TokenType::IDENT) {
+                return None;
+            }
+
+            let name = self.parse_identifier();
+
+            if !self.expect_peek(TokenType::ASSIGN) {
+                return None;
+            }
+
+            self.next_token();
+            let value = self.parse_expression(Precedence::Lowest)?;
+
+            if self.peek_token_is(&TokenType::SEMICOLON) {
+                self.next_token();
+            }
+
+            Some(Statement::Let { name, value })
+        }
+
+        fn parse_return_statement(&mut self) -> Option<Statement> {
+            self.next_token();
+            let return_value = self.parse_expression(Precedence::Lowest)?;
+
+            if self.peek_token_is(&TokenType::SEMICOLON) {
+                self.next_token();
+            }
+
+            Some(Statement::Return(return_value))
+        }
+
+        fn parse_expression_statement(&mut self) -> Option<Statement> {
+            let expression = self.parse_expression(Precedence::Lowest)?;
+
+            // The semicolon is optional so that `5 + 5` works in the REPL
+            if self.peek_token_is(&TokenType::SEMICOLON) {
+                self.next_token();
+            }
+
+            Some(Statement::Expression(expression))
+        }
+
+        fn parse_block_statement(&mut self) -> BlockStatement {
+            let mut statements = Vec::new();
+
+            self.next_token();
+
+            while !self.cur_token_is(&TokenType::RBRACE) && !self.cur_token_is(&TokenType::EOF) {
+                match self.parse_statement() {
+                    Some(stmt) => {
+                        statements.push(stmt);
+                        self.next_token();
+                    }
+                    None => self.synchronize(),
+                }
+            }
+
+            if self.cur_token_is(&TokenType::EOF) {
+                self.cur_error(ParseErrorKind::Expected(TokenType::RBRACE));
+            }
+
+            BlockStatement { statements }
+        }
+
+        // The heart of the Pratt parser: parse whatever prefix expression
+        // starts at the current token, then keep folding it into infix
+        // expressions for as long as the next operator binds tighter.
+        // Every expression ends on the current token once it's parsed,
+        // which is what its span is measured up to.
+        fn parse_expression(&mut self, precedence: Precedence) -> Option<Expression> {
+            let start = self.cur_token.span;
+            let kind = self.parse_prefix()?;
+            let mut left = Expression::new(kind, start.to(self.cur_token.span));
+
+            while !self.peek_token_is(&TokenType::SEMICOLON) && precedence < self.peek_precedence()
+            {
+                let start = left.span;
+                let kind = match self.peek_token.kind {
+                    TokenType::PLUS
+                    | TokenType::MINUS
+                    | TokenType::ASTERISK
+                    | TokenType::SLASH
+                    | TokenType::EQ
+                    | TokenType::NEQ
+                    | TokenType::LT
+                    | TokenType::GT
+                    | TokenType::LTE
+                    | TokenType::GTE
+                    | TokenType::AND
+                    | TokenType::OR
+                    | TokenType::PERCENT
+                    | TokenType::POWER => {
+                        self.next_token();
+                        self.parse_infix_expression(left)?
+                    }
+                    TokenType::LPAREN => {
+                        self.next_token();
+                        self.parse_call_expression(left)?
+                    }
+                    TokenType::LBRACKET => {
+                        self.next_token();
+                        self.parse_index_expression(left)?
+                    }
+                    _ => return Some(left),
+                };
+                left = Expression::new(kind, start.to(self.cur_token.span));
+            }
+
+            Some(left)
+        }
+
+        fn parse_prefix(&mut self) -> Option<ExpressionKind> {
+            match self.cur_token.kind {
+                TokenType::IDENT => Some(ExpressionKind::Identifier(self.parse_identifier())),
+                TokenType::INT => self.parse_integer_literal(),
+                TokenType::FLOAT => self.parse_float_literal(),
+                TokenType::STRING => Some(ExpressionKind::String(self.cur_token.literal.clone())),
+                TokenType::TRUE | TokenType::FALSE => {
+                    Some(ExpressionKind::Boolean(self.cur_token_is(&TokenType::TRUE)))
+                }
+                TokenType::BANG | TokenType::MINUS => self.parse_prefix_expression(),
+                TokenType::LPAREN => self.parse_grouped_expression(),
+                TokenType::IF => self.parse_if_expression(),
+                TokenType::FUNCTION => self.parse_function_literal(),
+                TokenType::LBRACKET => {
+                    let elements = self.parse_expression_list(TokenType::RBRACKET)?;
+                    Some(ExpressionKind::Array(elements))
+                }
+                TokenType::LBRACE => self.parse_hash_literal(),
+                _ => {
+                    self.cur_error(ParseErrorKind::ExpectedExpression);
+                    None
+                }
+            }
+        }
+
+        fn parse_identifier(&self) -> Identifier {
+            Identifier::new(self.cur_token.literal.clone(), self.cur_token.span)
+        }
+
+        fn parse_integer_literal(&mut self) -> Option<ExpressionKind> {
+            let literal = self.cur_token.literal.replace('_', "");
+            let (radix, digits) = match literal.get(..2) {
+                Some("0x") | Some("0X") => (16, &literal[2..]),
+                Some("0o") | Some("0O") => (8, &literal[2..]),
+                Some("0b") | Some("0B") => (2, &literal[2..]),
+                _ => (10, literal.as_str()),
+            };
+
+            match i64::from_str_radix(digits, radix) {
+                Ok(value) => Some(ExpressionKind::Integer(value)),
+                Err(err) => {
+                    let kind = match err.kind() {
+                        std::num::IntErrorKind::PosOverflow => ParseErrorKind::IntegerOverflow,
+                        _ => ParseErrorKind::InvalidInteger,
+                    };
+                    self.cur_error(kind);
+                    None
+                }
+            }
+        }
+
+        fn parse_float_literal(&mut self) -> Option<ExpressionKind> {
+            match self.cur_token.literal.replace('_', "").parse::<f64>() {
+                // Rounds to infinity instead of failing, which wouldn't
+                // print back as the literal that was written
+                Ok(value) if value.is_infinite() => {
+                    self.cur_error(ParseErrorKind::FloatOverflow);
+                    None
+                }
+                Ok(value) => Some(ExpressionKind::Float(value)),
+                Err(_) => {
+                    self.cur_error(ParseErrorKind::InvalidFloat);
+                    None
+                }
+            }
+        }
+
+        fn parse_prefix_expression(&mut self) -> Option<ExpressionKind> {
+            let operator = self.cur_token.kind.clone();
+
+            self.next_token();
+            let right = self.parse_expression(Precedence::Prefix)?;
+
+            Some(ExpressionKind::Prefix {
+                operator,
+                right: Box::new(right),
+            })
+        }
+
+        fn parse_infix_expression(&mut self, left: Expression) -> Option<ExpressionKind> {
+            let operator = self.cur_token.kind.clone();
+            let precedence = match operator {
+                // `**` is right associative, so the right operand takes in
+                // any further `**` instead of stopping at it. It also binds
+                // tighter than a prefix on its left, so `-2 ** 2` is -(2 ** 2).
+                TokenType::POWER => Precedence::Prefix,
+                _ => self.cur_precedence(),
+            };
+
+            self.next_token();
+            let right = self.parse_expression(precedence)?;
+
+            Some(ExpressionKind::Infix {
+                left: Box::new(left),
+                operator,
+                right: Box::new(right),
+            })
+        }
+
+        // The parentheses don't get a node of their own,
+        // they just widen the span of what they enclose
+        fn parse_grouped_expression(&mut self) -> Option<ExpressionKind> {
+            self.next_token();
+            let expr = self.parse_expression(Precedence::Lowest)?;
+
+            if !self.expect_peek(TokenType::RPAREN) {
+                return None;
+            }
+
+            Some(expr.kind)
+        }
+
+        fn parse_if_expression(&mut self) -> Option<ExpressionKind> {
+            if !self.expect_peek(TokenType::LPAREN) {
+                return None;
+            }
+
+            self.next_token();
+            let condition = self.parse_expression(Precedence::Lowest)?;
+
+            if !self.expect_peek(TokenType::RPAREN) {
+                return None;
+            }
+
+            if !self.expect_peek(TokenType::LBRACE) {
+                return None;
+            }
+
+            let consequence = self.parse_block_statement();
+
+            let alternative = if self.peek_token_is(&TokenType::ELSE) {
+                self.next_token();
+
+                if !self.expect_peek(TokenType::LBRACE) {
+                    return None;
+                }
+
+                Some(self.parse_block_statement())
+            } else {
+                None
+            };
+
+            Some(ExpressionKind::If {
+                condition: Box::new(condition),
+                consequence,
+                alternative,
+            })
+        }
+
+        fn parse_function_literal(&mut self) -> Option<ExpressionKind> {
+            if !self.expect_peek(TokenType::LPAREN) {
+                return None;
+            }
+
+            let parameters = self.parse_function_parameters()?;
+
+            if !self.expect_peek(TokenType::LBRACE) {
+                return None;
+            }
+
+            let body = self.parse_block_statement();
+
+            Some(ExpressionKind::Function { parameters, body })
+        }
+
+        fn parse_function_parameters(&mut self) -> Option<Vec<Identifier>> {
+            let mut identifiers = Vec::new();
+
+            if self.peek_token_is(&TokenType::RPAREN) {
+                self.next_token();
+                return Some(identifiers);
+            }
+
+            if !self.expect_peek(TokenType::IDENT) {
+                return None;
+            }
+            identifiers.push(self.parse_identifier());
+
+            while self.peek_token_is(&TokenType::COMMA) {
+                self.next_token();
+                if !self.expect_peek(TokenType::IDENT) {
+                    return None;
+                }
+                identifiers.push(self.parse_identifier());
+            }
+
+            if !self.expect_peek(TokenType::RPAREN) {
+                return None;
+            }
+
+            Some(identifiers)
+        }
+
+        fn parse_call_expression(&mut self, function: Expression) -> Option<ExpressionKind> {
+            let arguments = self.parse_expression_list(TokenType::RPAREN)?;
+
+            Some(ExpressionKind::Call {
+                function: Box::new(function),
+                arguments,
+            })
+        }
+
+        // Parses comma separated expressions up to the given closing token,
+        // as used by call arguments and array literals
+        fn parse_expression_list(&mut self, end: TokenType) -> Option<Vec<Expression>> {
+            let mut args = Vec::new();
+
+            if self.peek_token_is(&end) {
+                self.next_token();
+                return Some(args);
+            }
+
+            self.next_token();
+            args.push(self.parse_expression(Precedence::Lowest)?);
+
+            while self.peek_token_is(&TokenType::COMMA) {
+                self.next_token();
+                self.next_token();
+                args.push(self.parse_expression(Precedence::Lowest)?);
+            }
+
+            if !self.expect_peek(end) {
+                return None;
+            }
+
+            Some(args)
+        }
+
+        fn parse_index_expression(&mut self, left: Expression) -> Option<ExpressionKind> {
+            self.next_token();
+            let index = self.parse_expression(Precedence::Lowest)?;
+
+            if !self.expect_peek(TokenType::RBRACKET) {
+                return None;
+            }
+
+            Some(ExpressionKind::Index {
+                left: Box::new(left),
+                index: Box::new(index),
+            })
+        }
+
+        fn parse_hash_literal(&mut self) -> Option<ExpressionKind> {
+            let mut pairs = Vec::new();
+
+            while !self.peek_token_is(&TokenType::RBRACE) {
+                self.next_token();
+                let key = self.parse_expression(Precedence::Lowest)?;
+
+                if !self.expect_peek(TokenType::COLON) {
+                    return None;
+                }
+
+                self.next_token();
+                let value = self.parse_expression(Precedence::Lowest)?;
+                pairs.push((key, value));
+
+                if !self.peek_token_is(&TokenType::RBRACE) && !self.expect_peek(TokenType::COMMA) {
+                    return None;
+                }
+            }
+
+            if !self.expect_peek(TokenType::RBRACE) {
+                return None;
+            }
+
+            Some(ExpressionKind::Hash(pairs))
+        }
+    }
+}
+
+pub mod object {
+    use super::{
+        ast::*,
+        builtins::Builtin,
+        code::{Instructions, SourceMap},
+        token::Span,
+    };
+    use std::cell::RefCell;
+    use std::collections::{BTreeMap, HashMap, HashSet};
+    use std::fmt;
+    use std::rc::Rc;
+    use std::sync::atomic::{AtomicUsize, Ordering};
+
+    // A user defined function value, closing over the
+    // environment it was defined in
+    #[derive(Clone)]
+    pub struct Function {
+        pub parameters: Vec<Identifier>,
+        pub body: BlockStatement,
+        pub env: Rc<RefCell<Environment>>,
+    }
+
+    // The captured environment is left out: it usually holds the
+    // function itself, so following it would never terminate
+    impl fmt::Debug for Function {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            f.debug_struct("Function")
+                .field("parameters", &self.parameters)
+                .field("body", &self.body)
+                .finish()
+        }
+    }
+
+    // Two functions are only equal if they also close over the very same scope
+    impl PartialEq for Function {
+        fn eq(&self, other: &Function) -> bool {
+            self.parameters == other.parameters
+                && self.body == other.body
+                && Rc::ptr_eq(&self.env, &other.env)
+        }
+    }
+
+    // A function body lowered to bytecode. It only lives in the constant
+    // pool; running code always sees it wrapped up in a Closure.
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct CompiledFunction {
+        pub instructions: Instructions,
+        pub num_locals: usize, // parameters included
+        pub num_parameters: usize,
+        pub spans: SourceMap,
+    }
+
+    // A compiled function along with the values of the free variables
+    // it refers to, captured when the closure was made
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct Closure {
+        pub function: Rc<CompiledFunction>,
+        pub free: Vec<Object>,
+    }
+
+    // The subset of values that can be used as keys of a hash. Keeping
+    // them ordered means a hash always inspects the same way.
+    #[derive(Debug, PartialEq, Eq, PartialOrd, Ord, Hash, Clone)]
+    pub enum HashKey {
+        Integer(i64),
+        Boolean(bool),
+        String(String),
+    }
+
+    impl fmt::Display for HashKey {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                HashKey::Integer(value) => write!(f, "{}", value),
+                HashKey::Boolean(value) => write!(f, "{}", value),
+                HashKey::String(value) => write!(f, "{:?}", value),
+            }
+        }
+    }
+
+    // What went wrong during evaluation, and the expression it went wrong in.
+    // The span starts out empty and is filled in by the innermost expression
+    // the error passes through, which is the one that produced it.
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct RuntimeError {
+        pub message: String,
+        pub span: Option<Span>,
+    }
+
+    impl RuntimeError {
+        pub fn new(message: String) -> RuntimeError {
+            RuntimeError {
+                message,
+                span: None,
+            }
+        }
+    }
+
+    impl fmt::Display for RuntimeError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self.span {
+                Some(span) => write!(f, "{}: {}", span, self.message),
+                None => write!(f, "{}", self.message),
+            }
+        }
+    }
+
+    // Every value a Monkey program can produce at runtime
+    #[derive(Debug, PartialEq, Clone)]
+    pub enum Object {
+        Integer(i64),
+        Float(f64),
+        Boolean(bool),
+        String(String),
+        Null,
+        // Wraps the value of a `return` so it can unwind through nested blocks
+        ReturnValue(Box<Object>),
+        // A runtime error; like ReturnValue it stops evaluation wherever it's produced
+        Error(RuntimeError),
+        Function(Function),
+        // Shared rather than copied whenever a variable is read,
+        // which is safe as nothing changes them in place
+        Array(Rc<Vec<Object>>),
+        Hash(Rc<BTreeMap<HashKey, Object>>),
+        Builtin(Builtin),
+        // The bytecode counterparts of Function, produced by the vm
+        CompiledFunction(Rc<CompiledFunction>),
+        Closure(Rc<Closure>),
+    }
+
+    impl Object {
+        pub fn type_name(&self) -> &'static str {
+            match self {
+                Object::Integer(_) => "INTEGER",
+                Object::Float(_) => "FLOAT",
+                Object::Boolean(_) => "BOOLEAN",
+                Object::String(_) => "STRING",
+                Object::Null => "NULL",
+                Object::ReturnValue(_) => "RETURN_VALUE",
+                Object::Error(_) => "ERROR",
+                Object::Function(_) => "FUNCTION",
+                Object::Array(_) => "ARRAY",
+                Object::Hash(_) => "HASH",
+                Object::Builtin(_) => "BUILTIN",
+                Object::CompiledFunction(_) => "COMPILED_FUNCTION",
+                // To a Monkey program a closure is just a function
+                Object::Closure(_) => "FUNCTION",
+            }
+        }
+
+        // None if the value can't be used as a hash key
+        pub fn hash_key(&self) -> Option<HashKey> {
+            match self {
+                Object::Integer(value) => Some(HashKey::Integer(*value)),
+                Object::Boolean(value) => Some(HashKey::Boolean(*value)),
+                Object::String(value) => Some(HashKey::String(value.clone())),
+                _ => None,
+            }
+        }
+
+        pub fn is_error(&self) -> bool {
+            matches!(self, Object::Error(_))
+        }
+
+        // Everything except `false` and `null` counts as true
+        pub fn is_truthy(&self) -> bool {
+            !matches!(self, Object::Boolean(false) | Object::Null)
+        }
+    }
+
+    // The inspect form of a value, as the REPL shows it
+    impl fmt::Display for Object {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                Object::Integer(value) => write!(f, "{}", value),
+                Object::Float(value) => write!(f, "{:?}", value),
+                Object::Boolean(value) => write!(f, "{}", value),
+                Object::String(value) => write!(f, "{}", value),
+                Object::Null => write!(f, "null"),
+                Object::ReturnValue(value) => write!(f, "{}", value),
+                Object::Error(err) => write!(f, "ERROR: {}", err.message),
+                Object::Function(function) => {
+                    let params: Vec<String> =
+                        function.parameters.iter().map(|p| p.to_string()).collect();
+                    write!(f, "fn({}) {}", params.join(", "), function.body)
+                }
+                Object::Array(elements) => {
+                    let elements: Vec<String> = elements.iter().map(inspect_nested).collect();
+                    write!(f, "[{}]", elements.join(", "))
+                }
+                Object::Hash(pairs) => {
+                    let pairs: Vec<String> = pairs
+                        .iter()
+                        .map(|(key, value)| format!("{}: {}", key, inspect_nested(value)))
+                        .collect();
+                    write!(f, "{{{}}}", pairs.join(", "))
+                }
+                Object::Builtin(builtin) => write!(f, "builtin function {}", builtin.name),
+                Object::CompiledFunction(function) => {
+                    write!(f, "compiled function/{}", function.num_parameters)
+                }
+                Object::Closure(closure) => {
+                    write!(f, "closure/{}", closure.function.num_parameters)
+                }
+            }
+        }
+    }
+
+    // Strings inside arrays and hashes are quoted so that
+    // `["a, b"]` can be told apart from `["a", "b"]`
+    fn inspect_nested(value: &Object) -> String {
+        match value {
+            Object::String(value) => format!("{:?}", value),
+            value => value.to_string(),
+        }
+    }
+
+    // Numbers scopes in the order they're made
+    static NEXT_ENVIRONMENT_ID: AtomicUsize = AtomicUsize::new(0);
+
+    // Holds the values bound with `let` in one scope. Lookups that miss
+    // fall through to the enclosing (outer) scope, all the way up to the
+    // top level of the program.
+    #[derive(Debug)]
+    pub struct Environment {
+        id: usize,
+        store: HashMap<String, Object>,
+        outer: Option<Rc<RefCell<Environment>>>,
+    }
+
+    impl Default for Environment {
+        fn default() -> Environment {
+            Environment::new()
+        }
+    }
+
+    impl Environment {
+        pub fn new() -> Environment {
+            Environment {
+                id: NEXT_ENVIRONMENT_ID.fetch_add(1, Ordering::Relaxed),
+                store: HashMap::new(),
+                outer: None,
+            }
+        }
+
+        // A fresh scope nested inside outer, e.g. for the body of a function call
+        pub fn new_enclosed(outer: Rc<RefCell<Environment>>) -> Environment {
+            Environment {
+                outer: Some(outer),
+                ..Environment::new()
+            }
+        }
+
+        pub fn get(&self, name: &str) -> Option<Object> {
+            match self.store.get(name) {
+                Some(value) => Some(value.clone()),
+                None => self.outer.as_ref()?.borrow().get(name),
+            }
+        }
+
+        // Always binds in this scope, shadowing any outer binding of the same name
+        pub fn set(&mut self, name: &str, value: Object) {
+            self.store.insert(name.to_string(), value);
+        }
+
+        pub fn clear(&mut self) {
+            self.store.clear();
+        }
+    }
+
+    // Whether value holds on to scope, through the functions in it and the
+    // scopes they closed over. A function bound in a scope points back at
+    // it, so a call's scope has to be cleared once the call is over to be
+    // freed, unless the call's result still refers to it.
+    pub fn captures(value: &Object, scope: &Rc<RefCell<Environment>>) -> bool {
+        let oldest = scope.borrow().id;
+        captures_from(value, scope, oldest, &mut HashSet::new())
+    }
+
+    fn captures_from(
+        value: &Object,
+        scope: &Rc<RefCell<Environment>>,
+        oldest: usize,
+        seen: &mut HashSet<usize>,
+    ) -> bool {
+        match value {
+            Object::Function(function) => reaches(&function.env, scope, oldest, seen),
+            Object::ReturnValue(value) => captures_from(value, scope, oldest, seen),
+            Object::Array(elements) => elements
+                .iter()
+                .any(|element| captures_from(element, scope, oldest, seen)),
+            Object::Hash(pairs) => pairs
+                .values()
+                .any(|value| captures_from(value, scope, oldest, seen)),
+            _ => false,
+        }
+    }
+
+    // Scopes made before the one being looked for can't refer to it, as
+    // `let` only ever binds in the innermost scope, so they're skipped
+    fn reaches(
+        from: &Rc<RefCell<Environment>>,
+        scope: &Rc<RefCell<Environment>>,
+        oldest: usize,
+        seen: &mut HashSet<usize>,
+    ) -> bool {
+        if Rc::ptr_eq(from, scope) {
+            return true;
+        }
+        let from = from.borrow();
+        if from.id < oldest || !seen.insert(from.id) {
+            return false;
+        }
+
+        from.store
+            .values()
+            .any(|value| captures_from(value, scope, oldest, seen))
+            || from
+                .outer
+                .as_ref()
+                .is_some_and(|outer| reaches(outer, scope, oldest, seen))
+    }
+}
+
+pub mod builtins {
+    use super::object::*;
+    use std::fmt;
+    use std::rc::Rc;
+
+    pub type BuiltinFunction = fn(Vec<Object>) -> Object;
+
+    // A function implemented in Rust that Monkey code can call by name
+    #[derive(Clone, Copy)]
+    pub struct Builtin {
+        pub name: &'static str,
+        pub func: BuiltinFunction,
+    }
+
+    // Names are unique within BUILTINS, so they're enough to tell builtins apart
+    impl PartialEq for Builtin {
+        fn eq(&self, other: &Builtin) -> bool {
+            self.name == other.name
+        }
+    }
+
+    impl fmt::Debug for Builtin {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "Builtin({})", self.name)
+        }
+    }
+
+    pub const BUILTINS: &[Builtin] = &[
+        Builtin {
+            name: "len",
+            func: len,
+        },
+        Builtin {
+            name: "first",
+            func: first,
+        },
+        Builtin {
+            name: "last",
+            func: last,
+        },
+        Builtin {
+            name: "rest",
+            func: rest,
+        },
+        Builtin {
+            name: "push",
+            func: push,
+        },
+        Builtin {
+            name: "puts",
+            func: puts,
+        },
+    ];
+
+    fn error(message: String) -> Object {
+        Object::Error(RuntimeError::new(message))
+    }
+
+    pub fn lookup(name: &str) -> Option<Builtin> {
+        BUILTINS
+            .iter()
+            .find(|builtin| builtin.name == name)
+            .copied()
+    }
+
+    fn check_arity(args: &[Object], want: usize) -> Result<(), Object> {
+        if args.len() == want {
+            Ok(())
+        } else {
+            Err(error(format!(
+                "wrong number of arguments: want={}, got={}",
+                want,
+                args.len()
+            )))
+        }
+    }
+
+    // Hands back the elements of the first argument, or an error
+    // naming the builtin if it isn't an array
+    fn array_arg(name: &str, args: Vec<Object>) -> Result<Rc<Vec<Object>>, Object> {
+        match args.into_iter().next() {
+            Some(Object::Array(elements)) => Ok(elements),
+            Some(other) => Err(error(format!(
+                "argument to `{}` must be ARRAY, got {}",
+                name,
+                other.type_name()
+            ))),
+            None => Err(error(format!("argument to `{}` must be ARRAY", name))),
+        }
+    }
+
+    fn len(args: Vec<Object>) -> Object {
+        if let Err(err) = check_arity(&args, 1) {
+            return err;
+        }
+
+        match &args[0] {
+            Object::String(value) => Object::Integer(value.chars().count() as i64),
+            Object::Array(elements) => Object::Integer(elements.len() as i64),
+            Object::Hash(pairs) => Object::Integer(pairs.len() as i64),
+            other => error(format!(
+                "argument to `len` not supported, got {}",
+                other.type_name()
+            )),
+        }
+    }
+
+    fn first(args: Vec<Object>) -> Object {
+        if let Err(err) = check_arity(&args, 1) {
+            return err;
+        }
+
+        match array_arg("first", args) {
+            Ok(elements) => elements.first().cloned().unwrap_or(Object::Null),
+            Err(err) => err,
+        }
+    }
+
+    fn last(args: Vec<Object>) -> Object {
+        if let Err(err) = check_arity(&args, 1) {
+            return err;
+        }
+
+        match array_arg("last", args) {
+            Ok(elements) => elements.last().cloned().unwrap_or(Object::Null),
+            Err(err) => err,
+        }
+    }
+
+    // Every element but the first as a new array, or null for an empty array
+    fn rest(args: Vec<Object>) -> Object {
+        if let Err(err) = check_arity(&args, 1) {
+            return err;
+        }
+
+        match array_arg("rest", args) {
+            Ok(elements) if elements.is_empty() => Object::Null,
+            Ok(elements) => Object::Array(Rc::new(elements[1..].to_vec())),
+            Err(err) => err,
+        }
+    }
+
+    // A new array with the second argument appended; the original is left alone
+    fn push(args: Vec<Object>) -> Object {
+        if let Err(err) = check_arity(&args, 2) {
+            return err;
+        }
+
+        let mut args = args;
+        let value = args.pop().unwrap();
+        match array_arg("push", args) {
+            Ok(elements) => {
+                let mut elements = Rc::unwrap_or_clone(elements);
+                elements.push(value);
+                Object::Array(Rc::new(elements))
+            }
+            Err(err) => err,
+        }
+    }
+
+    // Prints each argument on its own line
+    fn puts(args: Vec<Object>) -> Object {
+        for arg in args.iter() {
+            println!("{}", arg);
+        }
+        Object::Null
+    }
+}
+
+pub mod eval {
+    use super::{ast::*, builtins, object::*, token::*};
+    use std::cell::{Cell, RefCell};
+    use std::collections::BTreeMap;
+    use std::convert::TryFrom;
+    use std::rc::Rc;
+
+    // How deep calls can nest before evaluation gives up with a stack
+    // overflow error, the same as the vm's limit
+    pub const MAX_CALL_DEPTH: usize = 1 << 13;
+
+    // How much native stack calls may use before evaluation gives up the
+    // same way. Each call takes a few kilobytes, and this leaves plenty
+    // to spare on a thread with the default 2 MB stack.
+    pub const DEFAULT_STACK_BUDGET: usize = 1 << 20;
+
+    thread_local! {
+        static CALL_DEPTH: Cell<usize> = const { Cell::new(0) };
+        // Where the native stack was when the outermost call began
+        static STACK_BASE: Cell<usize> = const { Cell::new(0) };
+        static STACK_BUDGET: Cell<usize> = const { Cell::new(DEFAULT_STACK_BUDGET) };
+    }
+
+    // Lets calls on the current thread use up to bytes of native stack,
+    // for threads that were given more than the default
+    pub fn set_stack_budget(bytes: usize) {
+        STACK_BUDGET.with(|budget| budget.set(bytes));
+    }
+
+    // The address of a local, which moves as the native stack grows
+    fn stack_position() -> usize {
+        let marker = 0u8;
+        std::hint::black_box(&marker) as *const u8 as usize
+    }
+
+    fn error(message: String) -> Object {
+        Object::Error(RuntimeError::new(message))
+    }
+
+    pub fn eval_program(program: &Program, env: &Rc<RefCell<Environment>>) -> Object {
+        let mut result = Object::Null;
+
+        for stmt in program.statements.iter() {
+            result = eval_statement(stmt, env);
+
+            match result {
+                Object::ReturnValue(value) => return *value,
+                Object::Error(_) => return result,
+                _ => {}
+            }
+        }
+
+        result
+    }
+
+    // Unlike eval_program, a block hands a ReturnValue back still wrapped
+    // so that the enclosing blocks stop evaluating as well
+    fn eval_block_statement(block: &BlockStatement, env: &Rc<RefCell<Environment>>) -> Object {
+        let mut result = Object::Null;
+
+        for stmt in block.statements.iter() {
+            result = eval_statement(stmt, env);
+
+            if let Object::ReturnValue(_) | Object::Error(_) = result {
+                return result;
+            }
+        }
+
+        result
+    }
+
+    fn eval_statement(stmt: &Statement, env: &Rc<RefCell<Environment>>) -> Object {
+        match stmt {
+            Statement::Let { name, value } => {
+                let value = eval_expression(value, env);
+                if value.is_error() {
+                    return value;
+                }
+                env.borrow_mut().set(&name.value, value);
+                Object::Null
+            }
+            Statement::Return(value) => {
+                let value = eval_expression(value, env);
+                if value.is_error() {
+                    return value;
+                }
+                Object::ReturnValue(Box::new(value))
+            }
+            Statement::Expression(expr) => eval_expression(expr, env),
+        }
+    }
+
+    // Any error that comes back without a location yet was
+    // produced by this very expression, so it gets its span
+    fn eval_expression(expr: &Expression, env: &Rc<RefCell<Environment>>) -> Object {
+        match eval_expression_kind(&expr.kind, env) {
+            Object::Error(mut err) if err.span.is_none() => {
+                err.span = Some(expr.span);
+                Object::Error(err)
+            }
+            value => value,
+        }
+    }
+
+    fn eval_expression_kind(kind: &ExpressionKind, env: &Rc<RefCell<Environment>>) -> Object {
+        match kind {
+            ExpressionKind::Integer(value) => Object::Integer(*value),
+            ExpressionKind::Float(value) => Object::Float(*value),
+            ExpressionKind::Boolean(value) => Object::Boolean(*value),
+            ExpressionKind::String(value) => Object::String(value.clone()),
+            ExpressionKind::Identifier(ident) => eval_identifier(ident, env),
+            ExpressionKind::Prefix { operator, right } => {
+                let right = eval_expression(right, env);
+                if right.is_error() {
+                    return right;
+                }
+                eval_prefix_expression(operator, right)
+            }
+            ExpressionKind::Infix {
+                left,
+                operator,
+                right,
+            } => {
+                let left = eval_expression(left, env);
+                if left.is_error() {
+                    return left;
+                }
+
+                // `&&` and `||` only look at the right side when the left
+                // doesn't already decide the result
+                match operator {
+                    TokenType::AND if !left.is_truthy() => return Object::Boolean(false),
+                    TokenType::OR if left.is_truthy() => return Object::Boolean(true),
+                    _ => {}
+                }
+
+                let right = eval_expression(right, env);
+                if right.is_error() {
+                    return right;
+                }
+                eval_infix_expression(operator, left, right)
+            }
+            ExpressionKind::If {
+                condition,
+                consequence,
+                alternative,
+            } => {
+                let condition = eval_expression(condition, env);
+                if condition.is_error() {
+                    return condition;
+                }
+
+                if condition.is_truthy() {
+                    eval_block_statement(consequence, env)
+                } else if let Some(alternative) = alternative {
+                    eval_block_statement(alternative, env)
+                } else {
+                    Object::Null
+                }
+            }
+            ExpressionKind::Function { parameters, body } => Object::Function(Function {
+                parameters: parameters.clone(),
+                body: body.clone(),
+                env: Rc::clone(env),
+            }),
+            ExpressionKind::Call {
+                function,
+                arguments,
+            } => {
+                let function = eval_expression(function, env);
+                if function.is_error() {
+                    return function;
+                }
+
+                let mut args = Vec::with_capacity(arguments.len());
+                for arg in arguments.iter() {
+                    let arg = eval_expression(arg, env);
+                    if arg.is_error() {
+                        return arg;
+                    }
+                    args.push(arg);
+                }
+
+                apply_function(function, args)
+            }
+            ExpressionKind::Array(elements) => {
+                let mut values = Vec::with_capacity(elements.len());
+                for element in elements.iter() {
+                    let value = eval_expression(element, env);
+                    if value.is_error() {
+                        return value;
+                    }
+                    values.push(value);
+                }
+                Object::Array(Rc::new(values))
+            }
+            ExpressionKind::Index { left, index } => {
+                let left = eval_expression(left, env);
+                if left.is_error() {
+                    return left;
+                }
+                let index = eval_expression(index, env);
+                if index.is_error() {
+                    return index;
+                }
+                eval_index_expression(left, index)
+            }
+            ExpressionKind::Hash(pairs) => eval_hash_literal(pairs, env),
+        }
+    }
+
+    // Out of range array indices and missing hash keys both produce null
+    pub fn eval_index_expression(left: Object, index: Object) -> Object {
+        match (left, index) {
+            (Object::Array(elements), Object::Integer(index)) => {
+                if index < 0 {
+                    return Object::Null;
+                }
+                elements
+                    .get(index as usize)
+                    .cloned()
+                    .unwrap_or(Object::Null)
+            }
+            (Object::Hash(pairs), key) => match key.hash_key() {
+                Some(key) => pairs.get(&key).cloned().unwrap_or(Object::Null),
+                None => error(format!("unusable as hash key: {}", key.type_name())),
+            },
+            (left, _) => error(format!(
+                "index operator not supported: {}",
+                left.type_name()
+            )),
+        }
+    }
+
+    fn eval_hash_literal(
+        pairs: &[(Expression, Expression)],
+        env: &Rc<RefCell<Environment>>,
+    ) -> Object {
+        let mut hash = BTreeMap::new();
+
+        for (key, value) in pairs.iter() {
+            let key = eval_expression(key, env);
+            if key.is_error() {
+                return key;
+            }
+            let hash_key = match key.hash_key() {
+                Some(hash_key) => hash_key,
+                None => return error(format!("unusable as hash key: {}", key.type_name())),
+            };
+
+            let value = eval_expression(value, env);
+            if value.is_error() {
+                return value;
+            }
+            hash.insert(hash_key, value);
+        }
+
+        Object::Hash(Rc::new(hash))
+    }
+
+    // Bindings in scope take priority, so a program is free
+    // to shadow any of the builtins with its own definition
+    fn eval_identifier(ident: &Identifier, env: &Rc<RefCell<Environment>>) -> Object {
+        if let Some(value) = env.borrow().get(&ident.value) {
+            return value;
+        }
+
+        match builtins::lookup(&ident.value) {
+            Some(builtin) => Object::Builtin(builtin),
+            None => error(format!("identifier not found: {}", ident.value)),
+        }
+    }
+
+    pub fn eval_prefix_expression(operator: &TokenType, right: Object) -> Object {
+        match (operator, right) {
+            (TokenType::BANG, right) => Object::Boolean(!right.is_truthy()),
+            (TokenType::MINUS, Object::Integer(value)) => match value.checked_neg() {
+                Some(value) => Object::Integer(value),
+                None => error(format!("integer overflow: -{}", value)),
+            },
+            (TokenType::MINUS, Object::Float(value)) => Object::Float(-value),
+            (operator, right) => error(format!(
+                "unknown operator: {}{}",
+                operator.literal(),
+                right.type_name()
+            )),
+        }
+    }
+
+    pub fn eval_infix_expression(operator: &TokenType, left: Object, right: Object) -> Object {
+        if let TokenType::AND | TokenType::OR = operator {
+            // The left side has already been found not to decide it
+            return Object::Boolean(right.is_truthy());
+        }
+
+        match (left, right) {
+            (Object::Integer(left), Object::Integer(right)) => {
+                eval_integer_infix_expression(operator, left, right)
+            }
+            // An integer meeting a float is promoted to a float
+            (Object::Float(left), Object::Float(right)) => {
+                eval_float_infix_expression(operator, left, right)
+            }
+            (Object::Integer(left), Object::Float(right)) => {
+                eval_float_infix_expression(operator, left as f64, right)
+            }
+            (Object::Float(left), Object::Integer(right)) => {
+                eval_float_infix_expression(operator, left, right as f64)
+            }
+            (Object::Boolean(left), Object::Boolean(right)) => match operator {
+                TokenType::EQ => Object::Boolean(left == right),
+                TokenType::NEQ => Object::Boolean(left != right),
+                _ => error(format!(
+                    "unknown operator: BOOLEAN {} BOOLEAN",
+                    operator.literal()
+                )),
+            },
+            (Object::String(left), Object::String(right)) => match operator {
+                TokenType::PLUS => Object::String(left + &right),
+                TokenType::EQ => Object::Boolean(left == right),
+                TokenType::NEQ => Object::Boolean(left != right),
+                _ => error(format!(
+                    "unknown operator: STRING {} STRING",
+                    operator.literal()
+                )),
+            },
+            (left, right) if left.type_name() != right.type_name() => error(format!(
+                "type mismatch: {} {} {}",
+                left.type_name(),
+                operator.literal(),
+                right.type_name()
+            )),
+            (left, right) => error(format!(
+                "unknown operator: {} {} {}",
+                left.type_name(),
+                operator.literal(),
+                right.type_name()
+            )),
+        }
+    }
+
+    fn eval_integer_infix_expression(operator: &TokenType, left: i64, right: i64) -> Object {
+        let result = match operator {
+            TokenType::PLUS => left.checked_add(right),
+            TokenType::MINUS => left.checked_sub(right),
+            TokenType::ASTERISK => left.checked_mul(right),
+            TokenType::SLASH | TokenType::PERCENT if right == 0 => {
+                return error("division by zero".to_string());
+            }
+            TokenType::SLASH => left.checked_div(right),
+            TokenType::PERCENT => left.checked_rem(right),
+            // A negative power of an integer is a fraction
+            TokenType::POWER if right < 0 => {
+                return Object::Float((left as f64).powf(right as f64));
+            }
+            TokenType::POWER => u32::try_from(right)
+                .ok()
+                .and_then(|right| left.checked_pow(right)),
+            TokenType::LT => return Object::Boolean(left < right),
+            TokenType::GT => return Object::Boolean(left > right),
+            TokenType::LTE => return Object::Boolean(left <= right),
+            TokenType::GTE => return Object::Boolean(left >= right),
+            TokenType::EQ => return Object::Boolean(left == right),
+            TokenType::NEQ => return Object::Boolean(left != right),
+            _ => {
+                return error(format!(
+                    "unknown operator: INTEGER {} INTEGER",
+                    operator.literal()
+                ))
+            }
+        };
+
+        match result {
+            Some(value) => Object::Integer(value),
+            None => error(format!(
+                "integer overflow: {} {} {}",
+                left,
+                operator.literal(),
+                right
+            )),
+        }
+    }
+
+    fn eval_float_infix_expression(operator: &TokenType, left: f64, right: f64) -> Object {
+        match operator {
+            TokenType::PLUS => Object::Float(left + right),
+            TokenType::MINUS => Object::Float(left - right),
+            TokenType::ASTERISK => Object::Float(left * right),
+            TokenType::SLASH | TokenType::PERCENT if right == 0.0 => {
+                error("division by zero".to_string())
+            }
+            TokenType::SLASH => Object::Float(left / right),
+            TokenType::PERCENT => Object::Float(left % right),
+            TokenType::POWER => Object::Float(left.powf(right)),
+            TokenType::LT => Object::Boolean(left < right),
+            TokenType::GT => Object::Boolean(left > right),
+            TokenType::LTE => Object::Boolean(left <= right),
+            TokenType::GTE => Object::Boolean(left >= right),
+            TokenType::EQ => Object::Boolean(left == right),
+            TokenType::NEQ => Object::Boolean(left != right),
+            _ => error(format!(
+                "unknown operator: FLOAT {} FLOAT",
+                operator.literal()
+            )),
+        }
+    }
+
+    fn apply_function(function: Object, args: Vec<Object>) -> Object {
+        let function = match function {
+            Object::Function(function) => function,
+            Object::Builtin(builtin) => return (builtin.func)(args),
+            other => return error(format!("not a function: {}", other.type_name())),
+        };
+
+        if function.parameters.len() != args.len() {
+            return error(format!(
+                "wrong number of arguments: want={}, got={}",
+                function.parameters.len(),
+                args.len()
+            ));
+        }
+
+        // Parameters are bound in a new scope on top of the one the
+        // function was defined in, not the one it's being called from
+        let mut env = Environment::new_enclosed(Rc::clone(&function.env));
+        for (param, arg) in function.parameters.iter().zip(args) {
+            env.set(&param.value, arg);
+        }
+
+        let depth = CALL_DEPTH.with(|depth| depth.get());
+        let position = stack_position();
+        if depth == 0 {
+            STACK_BASE.with(|base| base.set(position));
+        }
+        let used = STACK_BASE.with(|base| base.get().abs_diff(position));
+        if depth >= MAX_CALL_DEPTH || used > STACK_BUDGET.with(|budget| budget.get()) {
+            return error("stack overflow".to_string());
+        }
+
+        // A return inside the body only unwinds as far as this call
+        let env = Rc::new(RefCell::new(env));
+        CALL_DEPTH.with(|call_depth| call_depth.set(depth + 1));
+        let result = match eval_block_statement(&function.body, &env) {
+            Object::ReturnValue(value) => *value,
+            result => result,
+        };
+        CALL_DEPTH.with(|call_depth| call_depth.set(depth));
+
+        if !captures(&result, &env) {
+            env.borrow_mut().clear();
+        }
+        result
+    }
+}
+
+pub mod code {
+    use super::token::Span;
+    use std::fmt;
+
+    // Encoded bytecode: each instruction is an opcode byte followed by its
+    // operands, big endian and as wide as the opcode's definition says
+    #[derive(Debug, PartialEq, Clone, Default)]
+    pub struct Instructions(pub Vec<u8>);
+
+    impl Instructions {
+        pub fn new() -> Instructions {
+            Instructions::default()
+        }
+
+        pub fn len(&self) -> usize {
+            self.0.len()
+        }
+
+        pub fn is_empty(&self) -> bool {
+            self.0.is_empty()
+        }
+    }
+
+    // Where the instructions came from in the source: the offset each
+    // one starts at and the expression it was compiled from, in order
+    pub type SourceMap = Vec<(usize, Span)>;
+
+    // The span of the instruction that the byte at offset belongs to
+    pub fn span_at(spans: &[(usize, Span)], offset: usize) -> Option<Span> {
+        let index = spans.partition_point(|(start, _)| *start <= offset);
+        index.checked_sub(1).map(|index| spans[index].1)
+    }
+
+    // One instruction per line as `0000 OpConstant 1`, with the offset of
+    // the instruction first so jump targets can be followed by eye
+    impl fmt::Display for Instructions {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            let mut offset = 0;
+            while offset < self.0.len() {
+                let op = match Opcode::from_byte(self.0[offset]) {
+                    Some(op) => op,
+                    None => {
+                        writeln!(f, "{:04} ERROR: unknown opcode {}", offset, self.0[offset])?;
+                        offset += 1;
+                        continue;
+                    }
+                };
+
+                let definition = op.definition();
+                let width: usize = definition.operand_widths.iter().sum();
+                if offset + 1 + width > self.0.len() {
+                    writeln!(f, "{:04} ERROR: {} is cut short", offset, definition.name)?;
+                    break;
+                }
+
+                let (operands, read) = read_operands(&definition, &self.0[offset + 1..]);
+                write!(f, "{:04} {}", offset, definition.name)?;
+                for operand in operands.iter() {
+                    write!(f, " {}", operand)?;
+                }
+                writeln!(f)?;
+                offset += 1 + read;
+            }
+            Ok(())
+        }
+    }
+
+    #[derive(Debug, PartialEq, Eq, Clone, Copy)]
+    pub enum Opcode {
+        Constant, // push constants[index]
+        Pop,      // pop and discard the top of the stack
+        Add,      // the binary operators pop two values and push one
+        Sub,
+        Mul,
+        Div,
+        Mod,
+        Pow,
+        Equal,
+        NotEqual,
+        LessThan,
+        LessEqual,
+        GreaterThan,
+        GreaterEqual,
+        Minus,          // -x
+        Bang,           // !x
+        True,           // push true
+        False,          // push false
+        Null,           // push null
+        Jump,           // jump to an absolute offset
+        JumpNotTruthy,  // pop, and jump if it wasn't truthy
+        GetGlobal,      // push globals[index]
+        SetGlobal,      // pop into globals[index]
+        GetLocal,       // push the local in slot index of the current frame
+        SetLocal,       // pop into that slot
+        GetBuiltin,     // push BUILTINS[index]
+        GetFree,        // push free variable index of the current closure
+        CurrentClosure, // push the closure being run, for recursion
+        Array,          // pop n values into an array
+        Hash,           // pop n values, alternating keys and values, into a hash
+        Index,          // pop an index and a collection, push the element
+        Call,           // call the function below n arguments
+        ReturnValue,    // return the top of the stack from the current function
+        Return,         // return null from the current function
+        Closure,        // wrap constants[index] with n free variables off the stack
+        // push globals[index], failing with the name in constants[name] if unset
+        GetGlobalChecked,
+    }
+
+    // Every opcode, in the order of their byte values
+    const OPCODES: &[Opcode] = &[
+        Opcode::Constant,
+        Opcode::Pop,
+        Opcode::Add,
+        Opcode::Sub,
+        Opcode::Mul,
+        Opcode::Div,
+        Opcode::Mod,
+        Opcode::Pow,
+        Opcode::Equal,
+        Opcode::NotEqual,
+        Opcode::LessThan,
+        Opcode::LessEqual,
+        Opcode::GreaterThan,
+        Opcode::GreaterEqual,
+        Opcode::Minus,
+        Opcode::Bang,
+        Opcode::True,
+        Opcode::False,
+        Opcode::Null,
+        Opcode::Jump,
+        Opcode::JumpNotTruthy,
+        Opcode::GetGlobal,
+        Opcode::SetGlobal,
+        Opcode::GetLocal,
+        Opcode::SetLocal,
+        Opcode::GetBuiltin,
+        Opcode::GetFree,
+        Opcode::CurrentClosure,
+        Opcode::Array,
+        Opcode::Hash,
+        Opcode::Index,
+        Opcode::Call,
+        Opcode::ReturnValue,
+        Opcode::Return,
+        Opcode::Closure,
+        Opcode::GetGlobalChecked,
+    ];
+
+    // The name of an opcode and the width in bytes of each of its operands
+    #[derive(Debug, PartialEq)]
+    pub struct Definition {
+        pub name: &'static str,
+        pub operand_widths: &'static [usize],
+    }
+
+    impl Opcode {
+        pub fn from_byte(byte: u8) -> Option<Opcode> {
+            OPCODES.get(byte as usize).copied()
+        }
+
+        pub fn definition(self) -> Definition {
+            let (name, operand_widths): (&'static str, &'static [usize]) = match self {
+                Opcode::Constant => ("OpConstant", &[2]),
+                Opcode::Pop => ("OpPop", &[]),
+                Opcode::Add => ("OpAdd", &[]),
+                Opcode::Sub => ("OpSub", &[]),
+                Opcode::Mul => ("OpMul", &[]),
+                Opcode::Div => ("OpDiv", &[]),
+                Opcode::Mod => ("OpMod", &[]),
+                Opcode::Pow => ("OpPow", &[]),
+                Opcode::Equal => ("OpEqual", &[]),
+                Opcode::NotEqual => ("OpNotEqual", &[]),
+                Opcode::LessThan => ("OpLessThan", &[]),
+                Opcode::LessEqual => ("OpLessEqual", &[]),
+                Opcode::GreaterThan => ("OpGreaterThan", &[]),
+                Opcode::GreaterEqual => ("OpGreaterEqual", &[]),
+                Opcode::Minus => ("OpMinus", &[]),
+                Opcode::Bang => ("OpBang", &[]),
+                Opcode::True => ("OpTrue", &[]),
+                Opcode::False => ("OpFalse", &[]),
+                Opcode::Null => ("OpNull", &[]),
+                Opcode::Jump => ("OpJump", &[2]),
+                Opcode::JumpNotTruthy => ("OpJumpNotTruthy", &[2]),
+                Opcode::GetGlobal => ("OpGetGlobal", &[2]),
+                Opcode::SetGlobal => ("OpSetGlobal", &[2]),
+                Opcode::GetLocal => ("OpGetLocal", &[1]),
+                Opcode::SetLocal => ("OpSetLocal", &[1]),
+                Opcode::GetBuiltin => ("OpGetBuiltin", &[1]),
+                Opcode::GetFree => ("OpGetFree", &[1]),
+                Opcode::CurrentClosure => ("OpCurrentClosure", &[]),
+                Opcode::Array => ("OpArray", &[2]),
+                Opcode::Hash => ("OpHash", &[2]),
+                Opcode::Index => ("OpIndex", &[]),
+                Opcode::Call => ("OpCall", &[1]),
+                Opcode::ReturnValue => ("OpReturnValue", &[]),
+                Opcode::Return => ("OpReturn", &[]),
+                Opcode::Closure => ("OpClosure", &[2, 1]),
+                Opcode::GetGlobalChecked => ("OpGetGlobalChecked", &[2, 2]),
+            };
+            Definition {
+                name,
+                operand_widths,
+            }
+        }
+    }
+
+    // The largest operand that fits in width bytes
+    pub fn max_operand(width: usize) -> usize {
+        (1 << (8 * width)) - 1
+    }
+
+    // Encodes one instruction. Operands must fit their width, see
+    // max_operand, as anything bigger would be truncated.
+    pub fn make(op: Opcode, operands: &[usize]) -> Vec<u8> {
+        let definition = op.definition();
+        let mut instruction = vec![op as u8];
+
+        for (operand, width) in operands.iter().zip(definition.operand_widths) {
+            debug_assert!(
+                *operand <= max_operand(*width),
+                "operand {} too wide",
+                operand
+            );
+            match width {
+                2 => instruction.extend_from_slice(&(*operand as u16).to_be_bytes()),
+                1 => instruction.push(*operand as u8),
+                _ => unreachable!("no opcode has an operand {} bytes wide", width),
+            }
+        }
+        instruction
+    }
+
+    // Decodes the operands of an instruction whose opcode has already been
+    // read, returning them along with how many bytes they took up
+    pub fn read_operands(definition: &Definition, ins: &[u8]) -> (Vec<usize>, usize) {
+        let mut operands = Vec::with_capacity(definition.operand_widths.len());
+        let mut offset = 0;
+
+        for width in definition.operand_widths {
+            match width {
+                2 => operands.push(read_u16(&ins[offset..]) as usize),
+                1 => operands.push(ins[offset] as usize),
+                _ => unreachable!("no opcode has an operand {} bytes wide", width),
+            }
+            offset += width;
+        }
+        (operands, offset)
+    }
+
+    pub fn read_u16(ins: &[u8]) -> u16 {
+        u16::from_be_bytes([ins[0], ins[1]])
+    }
+}
+
+pub mod compiler {
+    use super::{ast::*, builtins::BUILTINS, code::*, object::*, token::*};
+    use std::collections::{HashMap, HashSet};
+    use std::rc::Rc;
+
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    pub enum SymbolScope {
+        Global,   // a top level binding, kept in the vm's globals
+        Local,    // a parameter or binding inside a function, kept on the stack
+        Builtin,  // one of BUILTINS
+        Free,     // a local of an enclosing function, captured by the closure
+        Function, // the name a function is being bound to, inside itself
+    }
+
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct Symbol {
+        pub name: String,
+        pub scope: SymbolScope,
+        pub index: usize,
+    }
+
+    // The names defined at one level of function nesting. Names that
+    // aren't found here are looked up in the enclosing table, and a local
+    // found there becomes a free variable of this function.
+    #[derive(Debug, Default, Clone)]
+    pub struct SymbolTable {
+        outer: Option<Box<SymbolTable>>,
+        store: HashMap<String, Symbol>,
+        // Globals used before any let for them was compiled
+        unbound: HashSet<String>,
+        pub num_definitions: usize,
+        pub free_symbols: Vec<Symbol>,
+    }
+
+    impl SymbolTable {
+        pub fn new() -> SymbolTable {
+            SymbolTable::default()
+        }
+
+        pub fn new_enclosed(outer: SymbolTable) -> SymbolTable {
+            SymbolTable {
+                outer: Some(Box::new(outer)),
+                ..SymbolTable::default()
+            }
+        }
+
+        // Hands back the enclosing table, if there is one
+        pub fn into_outer(self) -> Option<SymbolTable> {
+            self.outer.map(|outer| *outer)
+        }
+
+        // Binding a name again in the same scope reuses its slot
+        pub fn define(&mut self, name: &str) -> Symbol {
+            let scope = match self.outer {
+                Some(_) => SymbolScope::Local,
+                None => SymbolScope::Global,
+            };
+            if scope == SymbolScope::Global {
+                self.unbound.remove(name);
+            }
+            if let Some(symbol) = self.store.get(name) {
+                if symbol.scope == scope {
+                    return symbol.clone();
+                }
+            }
+
+            let symbol = Symbol {
+                name: name.to_string(),
+                scope,
+                index: self.num_definitions,
+            };
+            self.num_definitions += 1;
+            self.store.insert(name.to_string(), symbol.clone());
+            symbol
+        }
+
+        // Makes room for a global that a later top level let may bind,
+        // for a name that can't be resolved yet
+        pub fn define_unbound(&mut self, name: &str) -> Symbol {
+            match self.outer.as_mut() {
+                Some(outer) => outer.define_unbound(name),
+                None => {
+                    let symbol = self.define(name);
+                    self.unbound.insert(name.to_string());
+                    symbol
+                }
+            }
+        }
+
+        // Whether name is a global that no let has bound so far
+        pub fn is_unbound(&self, name: &str) -> bool {
+            match self.outer.as_ref() {
+                Some(outer) => outer.is_unbound(name),
+                None => self.unbound.contains(name),
+            }
+        }
+
+        pub fn define_builtin(&mut self, index: usize, name: &str) -> Symbol {
+            self.define_with(name, SymbolScope::Builtin, index)
+        }
+
+        pub fn define_function_name(&mut self, name: &str) -> Symbol {
+            self.define_with(name, SymbolScope::Function, 0)
+        }
+
+        fn define_free(&mut self, original: Symbol) -> Symbol {
+            let index = self.free_symbols.len();
+            let symbol = self.define_with(&original.name, SymbolScope::Free, index);
+            self.free_symbols.push(original);
+            symbol
+        }
+
+        fn define_with(&mut self, name: &str, scope: SymbolScope, index: usize) -> Symbol {
+            let symbol = Symbol {
+                name: name.to_string(),
+                scope,
+                index,
+            };
+            self.store.insert(name.to_string(), symbol.clone());
+            symbol
+        }
+
+        pub fn resolve(&mut self, name: &str) -> Option<Symbol> {
+            if let Some(symbol) = self.store.get(name) {
+                return Some(symbol.clone());
+            }
+
+            let symbol = self.outer.as_mut()?.resolve(name)?;
+            match symbol.scope {
+                SymbolScope::Global | SymbolScope::Builtin => Some(symbol),
+                _ => Some(self.define_free(symbol)),
+            }
+        }
+    }
+
+    // The output of the compiler: the top level instructions and
+    // the constants they refer to by index
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct Bytecode {
+        pub instructions: Instructions,
+        pub constants: Vec<Object>,
+        pub spans: SourceMap,
+    }
+
+    // Why a program couldn't be compiled, and the expression at fault
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct CompileError {
+        pub message: String,
+        pub span: Span,
+    }
+
+    impl std::fmt::Display for CompileError {
+        fn fmt(&self, f: &mut std::fmt::Formatter) -> std::fmt::Result {
+            write!(f, "{}: {}", self.span, self.message)
+        }
+    }
+
+    impl std::error::Error for CompileError {}
+
+    // Lists the main program, the constant pool, and then every compiled
+    // function in the pool. Functions nested inside other functions are
+    // constants too, so each shows up in its own section.
+    pub fn disassemble(bytecode: &Bytecode) -> String {
+        let mut out = format!("== main ==\n{}", bytecode.instructions);
+
+        if !bytecode.constants.is_empty() {
+            out.push_str("== constants ==\n");
+        }
+        for (index, constant) in bytecode.constants.iter().enumerate() {
+            match constant {
+                Object::String(value) => {
+                    out.push_str(&format!("{:04} STRING {:?}\n", index, value))
+                }
+                // Listed with their instructions below
+                Object::CompiledFunction(_) => {
+                    out.push_str(&format!("{:04} COMPILED_FUNCTION\n", index))
+                }
+                _ => out.push_str(&format!(
+                    "{:04} {} {}\n",
+                    index,
+                    constant.type_name(),
+                    constant
+                )),
+            }
+        }
+
+        for (index, constant) in bytecode.constants.iter().enumerate() {
+            if let Object::CompiledFunction(function) = constant {
+                out.push_str(&format!(
+                    "== function {} (parameters: {}, locals: {}) ==\n{}",
+                    index, function.num_parameters, function.num_locals, function.instructions
+                ));
+            }
+        }
+        out
+    }
+
+    // Compiles a whole program in one go
+    pub fn compile(program: &Program) -> Result<Bytecode, CompileError> {
+        let mut compiler = Compiler::new();
+        compiler.compile(program)?;
+        Ok(compiler.bytecode())
+    }
+
+    #[derive(Debug, Clone, Copy)]
+    struct EmittedInstruction {
+        opcode: Opcode,
+        position: usize,
+    }
+
+    // The instructions of the function being compiled, plus the last two
+    // emitted so an if or function body can take back a trailing OpPop
+    #[derive(Debug, Default)]
+    struct CompilationScope {
+        instructions: Instructions,
+        spans: SourceMap,
+        last: Option<EmittedInstruction>,
+        previous: Option<EmittedInstruction>,
+    }
+
+    pub struct Compiler {
+        // The expression being compiled, for errors about it
+        span: Span,
+        constants: Vec<Object>,
+        symbol_table: SymbolTable,
+        scopes: Vec<CompilationScope>,
+    }
+
+    impl Default for Compiler {
+        fn default() -> Compiler {
+            Compiler::new()
+        }
+    }
+
+    impl Compiler {
+        pub fn new() -> Compiler {
+            let mut symbol_table = SymbolTable::new();
+            for (index, builtin) in BUILTINS.iter().enumerate() {
+                symbol_table.define_builtin(index, builtin.name);
+            }
+            Compiler::new_with_state(symbol_table, Vec::new())
+        }
+
+        // Picks up where an earlier compiler left off, so the REPL can
+        // compile line by line against the same globals and constants
+        pub fn new_with_state(symbol_table: SymbolTable, constants: Vec<Object>) -> Compiler {
+            Compiler {
+                span: Span::default(),
+                constants,
+                symbol_table,
+                scopes: vec![CompilationScope::default()],
+            }
+        }
+
+        pub fn compile(&mut self, program: &Program) -> Result<(), CompileError> {
+            for stmt in program.statements.iter() {
+                self.compile_statement(stmt)?;
+            }
+            Ok(())
+        }
+
+        pub fn bytecode(&self) -> Bytecode {
+            Bytecode {
+                instructions: self.scopes[0].instructions.clone(),
+                constants: self.constants.clone(),
+                spans: self.scopes[0].spans.clone(),
+            }
+        }
+
+        // The names and constants to carry over into the next compiler
+        pub fn into_state(mut self) -> (SymbolTable, Vec<Object>) {
+            while self.scopes.len() > 1 {
+                self.leave_scope();
+            }
+            (self.symbol_table, self.constants)
+        }
+
+        fn compile_statement(&mut self, stmt: &Statement) -> Result<(), CompileError> {
+            match stmt {
+                Statement::Expression(expr) => {
+                    self.compile_expression(expr)?;
+                    self.emit(Opcode::Pop, &[])?;
+                }
+                Statement::Let { name, value } => {
+                    match &value.kind {
+                        ExpressionKind::Function { parameters, body } => {
+                            self.compile_function(parameters, body, Some(&name.value))?
+                        }
+                        _ => self.compile_expression(value)?,
+                    }
+
+                    // Defined after the value, so `let x = x` can't see itself
+                    let symbol = self.symbol_table.define(&name.value);
+                    match symbol.scope {
+                        SymbolScope::Global => self.emit(Opcode::SetGlobal, &[symbol.index]),
+                        _ => self.emit(Opcode::SetLocal, &[symbol.index]),
+                    }?;
+                }
+                Statement::Return(value) => {
+                    self.compile_expression(value)?;
+                    self.emit(Opcode::ReturnValue, &[])?;
+                }
+            }
+            Ok(())
+        }
+
+        fn compile_block(&mut self, block: &BlockStatement) -> Result<(), CompileError> {
+            for stmt in block.statements.iter() {
+                self.compile_statement(stmt)?;
+            }
+            Ok(())
+        }
+
+        // Compiles a block that produces a value, like an if branch. The
+        // value is that of a trailing expression statement, otherwise null.
+        fn compile_block_value(&mut self, block: &BlockStatement) -> Result<(), CompileError> {
+            self.compile_block(block)?;
+            if self.last_instruction_is(Opcode::Pop) {
+                self.remove_last_pop();
+            } else {
+                self.emit(Opcode::Null, &[])?;
+            }
+            Ok(())
+        }
+
+        fn compile_expression(&mut self, expr: &Expression) -> Result<(), CompileError> {
+            let outer = std::mem::replace(&mut self.span, expr.span);
+            match &expr.kind {
+                ExpressionKind::Identifier(ident) => {
+                    // A name with no binding yet may still get one from a
+                    // later top level let, as it would for the evaluator,
+                    // so the vm checks for it when the name is used
+                    let symbol = match self.symbol_table.resolve(&ident.value) {
+                        Some(symbol) => symbol,
+                        None => self.symbol_table.define_unbound(&ident.value),
+                    };
+                    self.load_symbol(&symbol)?;
+                }
+                ExpressionKind::Integer(value) => {
+                    let index = self.add_constant(Object::Integer(*value));
+                    self.emit(Opcode::Constant, &[index])?;
+                }
+                ExpressionKind::Float(value) => {
+                    let index = self.add_constant(Object::Float(*value));
+                    self.emit(Opcode::Constant, &[index])?;
+                }
+                ExpressionKind::String(value) => {
+                    let index = self.add_constant(Object::String(value.clone()));
+                    self.emit(Opcode::Constant, &[index])?;
+                }
+                ExpressionKind::Boolean(true) => {
+                    self.emit(Opcode::True, &[])?;
+                }
+                ExpressionKind::Boolean(false) => {
+                    self.emit(Opcode::False, &[])?;
+                }
+                ExpressionKind::Prefix { operator, right } => {
+                    self.compile_expression(right)?;
+                    match operator {
+                        TokenType::MINUS => self.emit(Opcode::Minus, &[]),
+                        TokenType::BANG => self.emit(Opcode::Bang, &[]),
+                        _ => return Err(unknown_operator(operator, expr.span)),
+                    }?;
+                }
+                ExpressionKind::Infix {
+                    left,
+                    operator: TokenType::AND,
+                    right,
+                } => {
+                    // left ? !!right : false
+                    self.compile_expression(left)?;
+                    let jump_not_truthy = self.emit(Opcode::JumpNotTruthy, &[9999])?;
+                    self.compile_truthiness(right)?;
+                    let jump = self.emit(Opcode::Jump, &[9999])?;
+                    self.change_operand(jump_not_truthy, self.current_instructions().len())?;
+                    self.emit(Opcode::False, &[])?;
+                    self.change_operand(jump, self.current_instructions().len())?;
+                }
+                ExpressionKind::Infix {
+                    left,
+                    operator: TokenType::OR,
+                    right,
+                } => {
+                    // left ? true : !!right
+                    self.compile_expression(left)?;
+                    let jump_not_truthy = self.emit(Opcode::JumpNotTruthy, &[9999])?;
+                    self.emit(Opcode::True, &[])?;
+                    let jump = self.emit(Opcode::Jump, &[9999])?;
+                    self.change_operand(jump_not_truthy, self.current_instructions().len())?;
+                    self.compile_truthiness(right)?;
+                    self.change_operand(jump, self.current_instructions().len())?;
+                }
+                ExpressionKind::Infix {
+                    left,
+                    operator,
+                    right,
+                } => {
+                    let opcode = match operator {
+                        TokenType::PLUS => Opcode::Add,
+                        TokenType::MINUS => Opcode::Sub,
+                        TokenType::ASTERISK => Opcode::Mul,
+                        TokenType::SLASH => Opcode::Div,
+                        TokenType::PERCENT => Opcode::Mod,
+                        TokenType::POWER => Opcode::Pow,
+                        TokenType::EQ => Opcode::Equal,
+                        TokenType::NEQ => Opcode::NotEqual,
+                        TokenType::LT => Opcode::LessThan,
+                        TokenType::LTE => Opcode::LessEqual,
+                        TokenType::GT => Opcode::GreaterThan,
+                        TokenType::GTE => Opcode::GreaterEqual,
+                        _ => return Err(unknown_operator(operator, expr.span)),
+                    };
+                    self.compile_expression(left)?;
+                    self.compile_expression(right)?;
+                    self.emit(opcode, &[])?;
+                }
+                ExpressionKind::If {
+                    condition,
+                    consequence,
+                    alternative,
+                } => {
+                    self.compile_expression(condition)?;
+                    let jump_not_truthy = self.emit(Opcode::JumpNotTruthy, &[9999])?;
+                    self.compile_block_value(consequence)?;
+
+                    let jump = self.emit(Opcode::Jump, &[9999])?;
+                    self.change_operand(jump_not_truthy, self.current_instructions().len())?;
+
+                    match alternative {
+                        Some(alternative) => self.compile_block_value(alternative)?,
+                        None => {
+                            self.emit(Opcode::Null, &[])?;
+                        }
+                    }
+                    self.change_operand(jump, self.current_instructions().len())?;
+                }
+                ExpressionKind::Function { parameters, body } => {
+                    self.compile_function(parameters, body, None)?
+                }
+                ExpressionKind::Call {
+                    function,
+                    arguments,
+                } => {
+                    self.compile_expression(function)?;
+                    for arg in arguments.iter() {
+                        self.compile_expression(arg)?;
+                    }
+                    self.emit(Opcode::Call, &[arguments.len()])?;
+                }
+                ExpressionKind::Array(elements) => {
+                    for element in elements.iter() {
+                        self.compile_expression(element)?;
+                    }
+                    self.emit(Opcode::Array, &[elements.len()])?;
+                }
+                ExpressionKind::Index { left, index } => {
+                    self.compile_expression(left)?;
+                    self.compile_expression(index)?;
+                    self.emit(Opcode::Index, &[])?;
+                }
+                ExpressionKind::Hash(pairs) => {
+                    for (key, value) in pairs.iter() {
+                        self.compile_expression(key)?;
+                        self.compile_expression(value)?;
+                    }
+                    self.emit(Opcode::Hash, &[pairs.len() * 2])?;
+                }
+            }
+            self.span = outer;
+            Ok(())
+        }
+
+        // Leaves true or false on the stack, as `!!expr` would
+        fn compile_truthiness(&mut self, expr: &Expression) -> Result<(), CompileError> {
+            self.compile_expression(expr)?;
+            self.emit(Opcode::Bang, &[])?;
+            self.emit(Opcode::Bang, &[])?;
+            Ok(())
+        }
+
+        // A function literal becomes a constant, and an OpClosure that pairs
+        // it with the free variables it uses. name is set when the literal is
+        // bound by a let, so the body can refer to itself.
+        fn compile_function(
+            &mut self,
+            parameters: &[Identifier],
+            body: &BlockStatement,
+            name: Option<&str>,
+        ) -> Result<(), CompileError> {
+            self.enter_scope();
+            if let Some(name) = name {
+                self.symbol_table.define_function_name(name);
+            }
+            for param in parameters.iter() {
+                self.symbol_table.define(&param.value);
+            }
+
+            if let Err(err) = self.compile_block(body) {
+                self.leave_scope();
+                return Err(err);
+            }
+            if self.last_instruction_is(Opcode::Pop) {
+                self.replace_last_pop_with_return();
+            }
+            if !self.last_instruction_is(Opcode::ReturnValue) {
+                self.emit(Opcode::Return, &[])?;
+            }
+
+            let free_symbols = self.symbol_table.free_symbols.clone();
+            let num_locals = self.symbol_table.num_definitions;
+            let scope = self.leave_scope();
+
+            for symbol in free_symbols.iter() {
+                self.load_symbol(symbol)?;
+            }
+
+            let function = CompiledFunction {
+                instructions: scope.instructions,
+                num_locals,
+                num_parameters: parameters.len(),
+                spans: scope.spans,
+            };
+            let index = self.add_constant(Object::CompiledFunction(Rc::new(function)));
+            self.emit(Opcode::Closure, &[index, free_symbols.len()])?;
+            Ok(())
+        }
+
+        fn load_symbol(&mut self, symbol: &Symbol) -> Result<(), CompileError> {
+            match symbol.scope {
+                SymbolScope::Global if self.symbol_table.is_unbound(&symbol.name) => {
+                    let name = self.add_constant(Object::String(symbol.name.clone()));
+                    self.emit(Opcode::GetGlobalChecked, &[symbol.index, name])
+                }
+                SymbolScope::Global => self.emit(Opcode::GetGlobal, &[symbol.index]),
+                SymbolScope::Local => self.emit(Opcode::GetLocal, &[symbol.index]),
+                SymbolScope::Builtin => self.emit(Opcode::GetBuiltin, &[symbol.index]),
+                SymbolScope::Free => self.emit(Opcode::GetFree, &[symbol.index]),
+                SymbolScope::Function => self.emit(Opcode::CurrentClosure, &[]),
+            }?;
+            Ok(())
+        }
+
+        fn add_constant(&mut self, obj: Object) -> usize {
+            self.constants.push(obj);
+            self.constants.len() - 1
+        }
+
+        // Appends an instruction to the current scope, returning its position.
+        // Fails when an operand is too big for its width, which is where
+        // limits like the size of the constant pool are enforced.
+        fn emit(&mut self, op: Opcode, operands: &[usize]) -> Result<usize, CompileError> {
+            self.check_operands(op, operands)?;
+            let instruction = make(op, operands);
+            let span = self.span;
+            let scope = self.scope_mut();
+            let position = scope.instructions.len();
+            scope.instructions.0.extend_from_slice(&instruction);
+            scope.spans.push((position, span));
+
+            scope.previous = scope.last;
+            scope.last = Some(EmittedInstruction {
+                opcode: op,
+                position,
+            });
+            Ok(position)
+        }
+
+        fn check_operands(&self, op: Opcode, operands: &[usize]) -> Result<(), CompileError> {
+            let widths = op.definition().operand_widths;
+            for (i, (operand, width)) in operands.iter().zip(widths).enumerate() {
+                if *operand > max_operand(*width) {
+                    return Err(CompileError {
+                        message: operand_too_large(op, i, max_operand(*width)),
+                        span: self.span,
+                    });
+                }
+            }
+            Ok(())
+        }
+
+        fn current_instructions(&self) -> &Instructions {
+            &self.scopes[self.scopes.len() - 1].instructions
+        }
+
+        fn scope_mut(&mut self) -> &mut CompilationScope {
+            let last = self.scopes.len() - 1;
+            &mut self.scopes[last]
+        }
+
+        fn last_instruction_is(&self, op: Opcode) -> bool {
+            match self.scopes[self.scopes.len() - 1].last {
+                Some(last) => last.opcode == op,
+                None => false,
+            }
+        }
+
+        fn remove_last_pop(&mut self) {
+            let scope = self.scope_mut();
+            if let Some(last) = scope.last {
+                scope.instructions.0.truncate(last.position);
+                scope
+                    .spans
+                    .retain(|(position, _)| *position < last.position);
+                scope.last = scope.previous;
+            }
+        }
+
+        fn replace_last_pop_with_return(&mut self) {
+            let scope = self.scope_mut();
+            if let Some(last) = scope.last.as_mut() {
+                scope.instructions.0[last.position] = Opcode::ReturnValue as u8;
+                last.opcode = Opcode::ReturnValue;
+            }
+        }
+
+        // Patches the operand of an already emitted instruction,
+        // e.g. a jump whose target wasn't known yet
+        fn change_operand(&mut self, position: usize, operand: usize) -> Result<(), CompileError> {
+            let op = Opcode::from_byte(self.current_instructions().0[position]).unwrap();
+            self.check_operands(op, &[operand])?;
+
+            let instruction = make(op, &[operand]);
+            let instructions = &mut self.scope_mut().instructions.0;
+            instructions[position..position + instruction.len()].copy_from_slice(&instruction);
+            Ok(())
+        }
+
+        fn enter_scope(&mut self) {
+            self.scopes.push(CompilationScope::default());
+            let outer = std::mem::take(&mut self.symbol_table);
+            self.symbol_table = SymbolTable::new_enclosed(outer);
+        }
+
+        fn leave_scope(&mut self) -> CompilationScope {
+            let scope = self.scopes.pop().unwrap_or_default();
+            let table = std::mem::take(&mut self.symbol_table);
+            self.symbol_table = table.into_outer().unwrap_or_default();
+            scope
+        }
+    }
+
+    // Says which limit a program went past, given the operand that didn't fit
+    fn operand_too_large(op: Opcode, operand: usize, max: usize) -> String {
+        match (op, operand) {
+            (Opcode::Constant, _) | (Opcode::Closure, 0) | (Opcode::GetGlobalChecked, 1) => {
+                format!("too many constants, the limit is {}", max + 1)
+            }
+            (Opcode::GetGlobal, _) | (Opcode::SetGlobal, _) | (Opcode::GetGlobalChecked, _) => {
+                format!("too many global bindings, the limit is {}", max + 1)
+            }
+            (Opcode::GetLocal, _) | (Opcode::SetLocal, _) => format!(
+                "too many local bindings in one function, the limit is {}",
+                max + 1
+            ),
+            (Opcode::GetFree, _) | (Opcode::Closure, _) => format!(
+                "too many free variables in one function, the limit is {}",
+                max
+            ),
+            (Opcode::Call, _) => format!("too many arguments in one call, the limit is {}", max),
+            (Opcode::Array, _) => {
+                format!(
+                    "too many elements in an array literal, the limit is {}",
+                    max
+                )
+            }
+            (Opcode::Hash, _) => {
+                format!("too many pairs in a hash literal, the limit is {}", max / 2)
+            }
+            (Opcode::Jump, _) | (Opcode::JumpNotTruthy, _) => format!(
+                "too much code in one function to jump across, the limit is {} bytes",
+                max
+            ),
+            _ => format!("operand of {} is larger than {}", op.definition().name, max),
+        }
+    }
+
+    fn unknown_operator(operator: &TokenType, span: Span) -> CompileError {
+        CompileError {
+            message: format!("unknown operator: {:?}", operator),
+            span,
+        }
+    }
+}
+
+pub mod vm {
+    use super::{
+        builtins::BUILTINS, code::*, compiler::Bytecode, eval, object::*, token::TokenType,
+    };
+    use std::collections::BTreeMap;
+    use std::rc::Rc;
+
+    const STACK_SIZE: usize = 1 << 16;
+    const MAX_FRAMES: usize = 1 << 13;
+
+    // One function call in progress. Its arguments and locals live on the
+    // stack from base_pointer up, just above the closure being called.
+    struct Frame {
+        closure: Rc<Closure>,
+        ip: usize,
+        base_pointer: usize,
+    }
+
+    pub struct Vm {
+        constants: Vec<Object>,
+        stack: Vec<Object>,
+        globals: Vec<Option<Object>>, // None until a let binds it
+        frames: Vec<Frame>,
+        last_popped: Object,
+    }
+
+    // Runs bytecode to completion, handing back the value of the
+    // last expression statement like eval_program does
+    pub fn run(bytecode: Bytecode) -> Result<Object, RuntimeError> {
+        let mut vm = Vm::new(bytecode);
+        vm.run()?;
+        Ok(vm.last_popped)
+    }
+
+    impl Vm {
+        pub fn new(bytecode: Bytecode) -> Vm {
+            Vm::new_with_globals(bytecode, Vec::new())
+        }
+
+        // Runs against the globals left behind by an earlier vm, for the REPL
+        pub fn new_with_globals(bytecode: Bytecode, globals: Vec<Option<Object>>) -> Vm {
+            let main = CompiledFunction {
+                instructions: bytecode.instructions,
+                num_locals: 0,
+                num_parameters: 0,
+                spans: bytecode.spans,
+            };
+            let main = Closure {
+                function: Rc::new(main),
+                free: Vec::new(),
+            };
+
+            Vm {
+                constants: bytecode.constants,
+                stack: Vec::new(),
+                globals,
+                frames: vec![Frame {
+                    closure: Rc::new(main),
+                    ip: 0,
+                    base_pointer: 0,
+                }],
+                last_popped: Object::Null,
+            }
+        }
+
+        // The value most recently popped off the stack, which after a run
+        // is the value of the program's last expression statement
+        pub fn last_popped(&self) -> &Object {
+            &self.last_popped
+        }
+
+        pub fn into_globals(self) -> Vec<Option<Object>> {
+            self.globals
+        }
+
+        // Errors are pinned to the expression whose instruction failed
+        pub fn run(&mut self) -> Result<(), RuntimeError> {
+            self.execute().map_err(|mut err| {
+                if err.span.is_none() {
+                    let frame = self.frame();
+                    err.span = span_at(&frame.closure.function.spans, frame.ip.saturating_sub(1));
+                }
+                err
+            })
+        }
+
+        fn execute(&mut self) -> Result<(), RuntimeError> {
+            while let Some(op) = self.fetch()? {
+                match op {
+                    Opcode::Constant => {
+                        let index = self.read_u16()?;
+                        let constant = self.constant(index)?.clone();
+                        self.push(constant)?;
+                    }
+                    Opcode::Pop => {
+                        self.last_popped = self.pop()?;
+                    }
+                    Opcode::Add
+                    | Opcode::Sub
+                    | Opcode::Mul
+                    | Opcode::Div
+                    | Opcode::Mod
+                    | Opcode::Pow
+                    | Opcode::Equal
+                    | Opcode::NotEqual
+                    | Opcode::LessThan
+                    | Opcode::LessEqual
+                    | Opcode::GreaterThan
+                    | Opcode::GreaterEqual => {
+                        let right = self.pop()?;
+                        let left = self.pop()?;
+                        let result = eval::eval_infix_expression(&infix_operator(op), left, right);
+                        self.push_result(result)?;
+                    }
+                    Opcode::Minus | Opcode::Bang => {
+                        let operator = match op {
+                            Opcode::Minus => TokenType::MINUS,
+                            _ => TokenType::BANG,
+                        };
+                        let right = self.pop()?;
+                        self.push_result(eval::eval_prefix_expression(&operator, right))?;
+                    }
+                    Opcode::True => self.push(Object::Boolean(true))?,
+                    Opcode::False => self.push(Object::Boolean(false))?,
+                    Opcode::Null => self.push(Object::Null)?,
+                    Opcode::Jump => {
+                        let target = self.read_u16()?;
+                        self.frame_mut().ip = target;
+                    }
+                    Opcode::JumpNotTruthy => {
+                        let target = self.read_u16()?;
+                        if !self.pop()?.is_truthy() {
+                            self.frame_mut().ip = target;
+                        }
+                    }
+                    Opcode::SetGlobal => {
+                        let index = self.read_u16()?;
+                        if index >= self.globals.len() {
+                            self.globals.resize(index + 1, None);
+                        }
+                        self.globals[index] = Some(self.pop()?);
+                    }
+                    Opcode::GetGlobal => {
+                        let index = self.read_u16()?;
+                        let value = self.globals.get(index).cloned().flatten();
+                        self.push(value.unwrap_or(Object::Null))?;
+                    }
+                    Opcode::GetGlobalChecked => {
+                        let index = self.read_u16()?;
+                        let name = self.read_u16()?;
+                        let value = match self.globals.get(index) {
+                            Some(Some(value)) => value.clone(),
+                            _ => {
+                                let name = match self.constant(name)? {
+                                    Object::String(name) => name.clone(),
+                                    other => other.to_string(),
+                                };
+                                return Err(RuntimeError::new(format!(
+                                    "identifier not found: {}",
+                                    name
+                                )));
+                            }
+                        };
+                        self.push(value)?;
+                    }
+                    Opcode::SetLocal => {
+                        let slot = self.frame().base_pointer + self.read_u8()?;
+                        let value = self.pop()?;
+                        *self.local(slot)? = value;
+                    }
+                    Opcode::GetLocal => {
+                        let slot = self.frame().base_pointer + self.read_u8()?;
+                        let value = self.local(slot)?.clone();
+                        self.push(value)?;
+                    }
+                    Opcode::GetBuiltin => {
+                        let index = self.read_u8()?;
+                        let builtin = BUILTINS
+                            .get(index)
+                            .ok_or_else(|| invalid_bytecode("no such builtin"))?;
+                        self.push(Object::Builtin(*builtin))?;
+                    }
+                    Opcode::GetFree => {
+                        let index = self.read_u8()?;
+                        let value = self.frame().closure.free.get(index).cloned();
+                        let value =
+                            value.ok_or_else(|| invalid_bytecode("no such free variable"))?;
+                        self.push(value)?;
+                    }
+                    Opcode::CurrentClosure => {
+                        let closure = Rc::clone(&self.frame().closure);
+                        self.push(Object::Closure(closure))?;
+                    }
+                    Opcode::Array => {
+                        let count = self.read_u16()?;
+                        let elements = self.pop_n(count)?;
+                        self.push(Object::Array(Rc::new(elements)))?;
+                    }
+                    Opcode::Hash => {
+                        let count = self.read_u16()?;
+                        let items = self.pop_n(count)?;
+                        let hash = build_hash(items)?;
+                        self.push(hash)?;
+                    }
+                    Opcode::Index => {
+                        let index = self.pop()?;
+                        let left = self.pop()?;
+                        self.push_result(eval::eval_index_expression(left, index))?;
+                    }
+                    Opcode::Call => {
+                        let num_args = self.read_u8()?;
+                        self.call(num_args)?;
+                    }
+                    Opcode::ReturnValue => {
+                        let value = self.pop()?;
+                        if self.return_from_frame(value) {
+                            return Ok(());
+                        }
+                    }
+                    Opcode::Return => {
+                        if self.return_from_frame(Object::Null) {
+                            return Ok(());
+                        }
+                    }
+                    Opcode::Closure => {
+                        let index = self.read_u16()?;
+                        let num_free = self.read_u8()?;
+                        let function = match self.constant(index)? {
+                            Object::CompiledFunction(function) => Rc::clone(function),
+                            other => {
+                                return Err(RuntimeError::new(format!(
+                                    "not a function: {}",
+                                    other.type_name()
+                                )))
+                            }
+                        };
+                        let free = self.pop_n(num_free)?;
+                        self.push(Object::Closure(Rc::new(Closure { function, free })))?;
+                    }
+                }
+            }
+            Ok(())
+        }
+
+        // Reads the next opcode of the current frame, or None at the
+        // end of the main program. Functions always end in a return,
+        // so running off the end of one means the bytecode is broken.
+        fn fetch(&mut self) -> Result<Option<Opcode>, RuntimeError> {
+            let is_main = self.frames.len() == 1;
+            let frame = self.frame_mut();
+            let byte = match frame.closure.function.instructions.0.get(frame.ip) {
+                Some(byte) => *byte,
+                None if is_main => return Ok(None),
+                None => return Err(invalid_bytecode("function ends without returning")),
+            };
+
+            frame.ip += 1;
+            match Opcode::from_byte(byte) {
+                Some(op) => Ok(Some(op)),
+                None => Err(invalid_bytecode(&format!(
+                    "unknown opcode {} at {:04}",
+                    byte,
+                    frame.ip - 1
+                ))),
+            }
+        }
+
+        fn read_u8(&mut self) -> Result<usize, RuntimeError> {
+            Ok(self.read_operand(1)?[0] as usize)
+        }
+
+        fn read_u16(&mut self) -> Result<usize, RuntimeError> {
+            Ok(read_u16(self.read_operand(2)?) as usize)
+        }
+
+        fn read_operand(&mut self, width: usize) -> Result<&[u8], RuntimeError> {
+            let frame = self.frames.last_mut().unwrap();
+            let start = frame.ip;
+            frame.ip += width;
+            frame
+                .closure
+                .function
+                .instructions
+                .0
+                .get(start..start + width)
+                .ok_or_else(|| invalid_bytecode(&format!("operand cut short at {:04}", start)))
+        }
+
+        fn constant(&self, index: usize) -> Result<&Object, RuntimeError> {
+            self.constants
+                .get(index)
+                .ok_or_else(|| invalid_bytecode(&format!("no constant {}", index)))
+        }
+
+        fn local(&mut self, slot: usize) -> Result<&mut Object, RuntimeError> {
+            self.stack
+                .get_mut(slot)
+                .ok_or_else(|| invalid_bytecode(&format!("no local in stack slot {}", slot)))
+        }
+
+        // Pops the top count values, in the order they were pushed
+        fn pop_n(&mut self, count: usize) -> Result<Vec<Object>, RuntimeError> {
+            match self.stack.len().checked_sub(count) {
+                Some(start) => Ok(self.stack.split_off(start)),
+                None => Err(invalid_bytecode("pop from an empty stack")),
+            }
+        }
+
+        fn frame(&self) -> &Frame {
+            &self.frames[self.frames.len() - 1]
+        }
+
+        fn frame_mut(&mut self) -> &mut Frame {
+            let last = self.frames.len() - 1;
+            &mut self.frames[last]
+        }
+
+        fn push(&mut self, obj: Object) -> Result<(), RuntimeError> {
+            if self.stack.len() >= STACK_SIZE {
+                return Err(RuntimeError::new("stack overflow".to_string()));
+            }
+            self.stack.push(obj);
+            Ok(())
+        }
+
+        // Pushes the result of an operation shared with the evaluator,
+        // which reports failure as an error object
+        fn push_result(&mut self, result: Object) -> Result<(), RuntimeError> {
+            match result {
+                Object::Error(err) => Err(err),
+                result => self.push(result),
+            }
+        }
+
+        fn pop(&mut self) -> Result<Object, RuntimeError> {
+            self.stack
+                .pop()
+                .ok_or_else(|| invalid_bytecode("pop from an empty stack"))
+        }
+
+        fn call(&mut self, num_args: usize) -> Result<(), RuntimeError> {
+            let callee = match self.stack.len().checked_sub(num_args + 1) {
+                Some(callee) => callee,
+                None => return Err(invalid_bytecode("pop from an empty stack")),
+            };
+
+            match self.stack[callee].clone() {
+                Object::Closure(closure) => {
+                    let function = &closure.function;
+                    if function.num_parameters != num_args {
+                        return Err(RuntimeError::new(format!(
+                            "wrong number of arguments: want={}, got={}",
+                            function.num_parameters, num_args
+                        )));
+                    }
+
+                    let base_pointer = callee + 1;
+                    let stack_top = base_pointer + function.num_locals;
+                    if self.frames.len() >= MAX_FRAMES || stack_top > STACK_SIZE {
+                        return Err(RuntimeError::new("stack overflow".to_string()));
+                    }
+
+                    self.stack.resize(stack_top, Object::Null);
+                    self.frames.push(Frame {
+                        closure,
+                        ip: 0,
+                        base_pointer,
+                    });
+                    Ok(())
+                }
+                Object::Builtin(builtin) => {
+                    let args = self.stack.split_off(callee + 1);
+                    self.stack.pop();
+                    self.push_result((builtin.func)(args))
+                }
+                other => Err(RuntimeError::new(format!(
+                    "not a function: {}",
+                    other.type_name()
+                ))),
+            }
+        }
+
+        // Pops the current frame along with its locals and the closure
+        // that was called, leaving value in their place. A return from the
+        // main program ends it, which is reported by returning true.
+        fn return_from_frame(&mut self, value: Object) -> bool {
+            if self.frames.len() == 1 {
+                self.last_popped = value;
+                return true;
+            }
+
+            let frame = self.frames.pop().unwrap();
+            self.stack.truncate(frame.base_pointer - 1);
+            self.stack.push(value);
+            false
+        }
+    }
+
+    // An error that well formed bytecode can't run into
+    fn invalid_bytecode(message: &str) -> RuntimeError {
+        RuntimeError::new(format!("invalid bytecode: {}", message))
+    }
+
+    fn infix_operator(op: Opcode) -> TokenType {
+        match op {
+            Opcode::Add => TokenType::PLUS,
+            Opcode::Sub => TokenType::MINUS,
+            Opcode::Mul => TokenType::ASTERISK,
+            Opcode::Div => TokenType::SLASH,
+            Opcode::Mod => TokenType::PERCENT,
+            Opcode::Pow => TokenType::POWER,
+            Opcode::Equal => TokenType::EQ,
+            Opcode::NotEqual => TokenType::NEQ,
+            Opcode::LessThan => TokenType::LT,
+            Opcode::LessEqual => TokenType::LTE,
+            Opcode::GreaterThan => TokenType::GT,
+            Opcode::GreaterEqual => TokenType::GTE,
+            _ => unreachable!("{:?} is not a binary operator", op),
+        }
+    }
+
+    fn build_hash(items: Vec<Object>) -> Result<Object, RuntimeError> {
+        let mut hash = BTreeMap::new();
+        let mut items = items.into_iter();
+
+        while let (Some(key), Some(value)) = (items.next(), items.next()) {
+            match key.hash_key() {
+                Some(key) => hash.insert(key, value),
+                None => {
+                    return Err(RuntimeError::new(format!(
+                        "unusable as hash key: {}",
+                        key.type_name()
+                    )))
+                }
+            };
+        }
+        Ok(Object::Hash(Rc::new(hash)))
+    }
+}
+
+pub mod mkc {
+    // The .mkc format for compiled programs, all integers big endian:
+    //
+    //     magic     b"MKC\0"
+    //     version   u16
+    //     constants u32 count, then per constant a tag byte and its value
+    //     main      u32 length, then that many bytes of instructions
+    //     spans     u32 count, then per instruction its offset and the
+    //               start, end, line and column of its span, each a u32
+    //     checksum  u32 FNV-1a of everything before it
+    //
+    // Compiled functions carry their own instructions and spans laid
+    // out the same way as main's.
+    // Constants are integers, floats, strings and compiled functions,
+    // which are all the compiler puts in the pool.
+    use super::{builtins::BUILTINS, code::*, compiler::Bytecode, object::*, token::Span};
+    use std::convert::TryInto;
+    use std::fmt;
+    use std::rc::Rc;
+
+    pub const MAGIC: &[u8; 4] = b"MKC\0";
+    // Bump whenever the layout or the opcode numbering changes
+    pub const VERSION: u16 = 3;
+
+    const TAG_INTEGER: u8 = 0;
+    const TAG_FLOAT: u8 = 1;
+    const TAG_STRING: u8 = 2;
+    const TAG_FUNCTION: u8 = 3;
+
+    #[derive(Debug, PartialEq, Clone)]
+    pub enum FormatError {
+        BadMagic,
+        VersionMismatch(u16),
+        ChecksumMismatch,
+        Truncated,
+        UnknownConstant(u8),
+        InvalidString,
+        UnsupportedConstant(&'static str),
+        InvalidInstructions(String),
+    }
+
+    impl fmt::Display for FormatError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match self {
+                FormatError::BadMagic => write!(f, "not a compiled monkey program"),
+                FormatError::VersionMismatch(found) => write!(
+                    f,
+                    "compiled with bytecode version {}, but this monkey runs version {}; recompile the script",
+                    found, VERSION
+                ),
+                FormatError::ChecksumMismatch => {
+                    write!(f, "checksum mismatch, the file is corrupt")
+                }
+                FormatError::Truncated => write!(f, "unexpected end of file"),
+                FormatError::UnknownConstant(tag) => write!(f, "unknown constant tag {}", tag),
+                FormatError::InvalidString => write!(f, "string constant is not valid UTF-8"),
+                FormatError::UnsupportedConstant(kind) => {
+                    write!(f, "{} constants can't be saved", kind)
+                }
+                FormatError::InvalidInstructions(message) => {
+                    write!(f, "invalid instructions: {}", message)
+                }
+            }
+        }
+    }
+
+    impl std::error::Error for FormatError {}
+
+    pub fn encode(bytecode: &Bytecode) -> Result<Vec<u8>, FormatError> {
+        let mut out = MAGIC.to_vec();
+        out.extend_from_slice(&VERSION.to_be_bytes());
+
+        put_u32(&mut out, bytecode.constants.len());
+        for constant in bytecode.constants.iter() {
+            match constant {
+                Object::Integer(value) => {
+                    out.push(TAG_INTEGER);
+                    out.extend_from_slice(&value.to_be_bytes());
+                }
+                Object::Float(value) => {
+                    out.push(TAG_FLOAT);
+                    out.extend_from_slice(&value.to_bits().to_be_bytes());
+                }
+                Object::String(value) => {
+                    out.push(TAG_STRING);
+                    put_bytes(&mut out, value.as_bytes());
+                }
+                Object::CompiledFunction(function) => {
+                    out.push(TAG_FUNCTION);
+                    put_u32(&mut out, function.num_locals);
+                    put_u32(&mut out, function.num_parameters);
+                    put_bytes(&mut out, &function.instructions.0);
+                    put_spans(&mut out, &function.spans);
+                }
+                other => return Err(FormatError::UnsupportedConstant(other.type_name())),
+            }
+        }
+        put_bytes(&mut out, &bytecode.instructions.0);
+        put_spans(&mut out, &bytecode.spans);
+
+        let checksum = fnv1a(&out);
+        out.extend_from_slice(&checksum.to_be_bytes());
+        Ok(out)
+    }
+
+    pub fn decode(bytes: &[u8]) -> Result<Bytecode, FormatError> {
+        if !bytes.starts_with(MAGIC) {
+            return Err(FormatError::BadMagic);
+        }
+        let mut reader = Reader {
+            bytes,
+            pos: MAGIC.len(),
+        };
+        // Checked before the checksum, since another version may
+        // not even put its checksum in the same place
+        let version = u16::from_be_bytes(reader.take_array()?);
+        if version != VERSION {
+            return Err(FormatError::VersionMismatch(version));
+        }
+
+        if bytes.len() < reader.pos + 4 {
+            return Err(FormatError::Truncated);
+        }
+        let (body, checksum) = bytes.split_at(bytes.len() - 4);
+        if fnv1a(body) != u32::from_be_bytes(checksum.try_into().unwrap()) {
+            return Err(FormatError::ChecksumMismatch);
+        }
+        reader.bytes = body;
+
+        let count = reader.take_u32()?;
+        let mut constants = Vec::new();
+        for _ in 0..count {
+            let constant = match reader.take(1)?[0] {
+                TAG_INTEGER => Object::Integer(i64::from_be_bytes(reader.take_array()?)),
+                TAG_FLOAT => {
+                    Object::Float(f64::from_bits(u64::from_be_bytes(reader.take_array()?)))
+                }
+                TAG_STRING => {
+                    let bytes = reader.take_bytes()?;
+                    match std::str::from_utf8(bytes) {
+                        Ok(value) => Object::String(value.to_string()),
+                        Err(_) => return Err(FormatError::InvalidString),
+                    }
+                }
+                TAG_FUNCTION => {
+                    let num_locals = reader.take_u32()?;
+                    let num_parameters = reader.take_u32()?;
+                    let instructions = Instructions(reader.take_bytes()?.to_vec());
+                    let spans = reader.take_spans()?;
+                    Object::CompiledFunction(Rc::new(CompiledFunction {
+                        instructions,
+                        num_locals,
+                        num_parameters,
+                        spans,
+                    }))
+                }
+                tag => return Err(FormatError::UnknownConstant(tag)),
+            };
+            constants.push(constant);
+        }
+        let instructions = Instructions(reader.take_bytes()?.to_vec());
+        let spans = reader.take_spans()?;
+
+        validate(&instructions, &constants)?;
+        for constant in constants.iter() {
+            if let Object::CompiledFunction(function) = constant {
+                validate(&function.instructions, &constants)?;
+            }
+        }
+
+        Ok(Bytecode {
+            instructions,
+            constants,
+            spans,
+        })
+    }
+
+    // The checksum only catches accidents, so instructions are checked
+    // before they're handed to the vm: every opcode must be known, with
+    // all of its operands present and pointing at things that exist.
+    fn validate(instructions: &Instructions, constants: &[Object]) -> Result<(), FormatError> {
+        let ins = &instructions.0;
+        let mut offset = 0;
+
+        while offset < ins.len() {
+            let invalid = |message: String| {
+                Err(FormatError::InvalidInstructions(format!(
+                    "{} at {:04}",
+                    message, offset
+                )))
+            };
+
+            let op = match Opcode::from_byte(ins[offset]) {
+                Some(op) => op,
+                None => return invalid(format!("unknown opcode {}", ins[offset])),
+            };
+            let definition = op.definition();
+            let width: usize = definition.operand_widths.iter().sum();
+            if offset + 1 + width > ins.len() {
+                return invalid(format!("{} is cut short", definition.name));
+            }
+
+            let (operands, read) = read_operands(&definition, &ins[offset + 1..]);
+            match op {
+                Opcode::Constant if operands[0] >= constants.len() => {
+                    return invalid(format!("no constant {}", operands[0]))
+                }
+                Opcode::Closure => match constants.get(operands[0]) {
+                    Some(Object::CompiledFunction(_)) => {}
+                    _ => return invalid(format!("constant {} is not a function", operands[0])),
+                },
+                Opcode::Jump | Opcode::JumpNotTruthy if operands[0] > ins.len() => {
+                    return invalid(format!("jump to {:04} is out of bounds", operands[0]))
+                }
+                Opcode::GetGlobalChecked => match constants.get(operands[1]) {
+                    Some(Object::String(_)) => {}
+                    _ => return invalid(format!("constant {} is not a name", operands[1])),
+                },
+                Opcode::GetBuiltin if operands[0] >= BUILTINS.len() => {
+                    return invalid(format!("no builtin {}", operands[0]))
+                }
+                _ => {}
+            }
+            offset += 1 + read;
+        }
+        Ok(())
+    }
+
+    // FNV-1a, 32 bit. Enough to catch a damaged or truncated file.
+    fn fnv1a(bytes: &[u8]) -> u32 {
+        bytes.iter().fold(0x811c_9dc5, |hash, byte| {
+            (hash ^ *byte as u32).wrapping_mul(0x0100_0193)
+        })
+    }
+
+    fn put_u32(out: &mut Vec<u8>, value: usize) {
+        out.extend_from_slice(&(value as u32).to_be_bytes());
+    }
+
+    fn put_bytes(out: &mut Vec<u8>, bytes: &[u8]) {
+        put_u32(out, bytes.len());
+        out.extend_from_slice(bytes);
+    }
+
+    fn put_spans(out: &mut Vec<u8>, spans: &[(usize, Span)]) {
+        put_u32(out, spans.len());
+        for (offset, span) in spans.iter() {
+            for value in [*offset, span.start, span.end, span.line, span.column].iter() {
+                put_u32(out, *value);
+            }
+        }
+    }
+
+    struct Reader<'a> {
+        bytes: &'a [u8],
+        pos: usize,
+    }
+
+    impl<'a> Reader<'a> {
+        fn take(&mut self, n: usize) -> Result<&'a [u8], FormatError> {
+            let end = self.pos.checked_add(n).ok_or(FormatError::Truncated)?;
+            let bytes = self
+                .bytes
+                .get(self.pos..end)
+                .ok_or(FormatError::Truncated)?;
+            self.pos = end;
+            Ok(bytes)
+        }
+
+        fn take_array<const N: usize>(&mut self) -> Result<[u8; N], FormatError> {
+            Ok(self.take(N)?.try_into().unwrap())
+        }
+
+        fn take_u32(&mut self) -> Result<usize, FormatError> {
+            Ok(u32::from_be_bytes(self.take_array()?) as usize)
+        }
+
+        fn take_bytes(&mut self) -> Result<&'a [u8], FormatError> {
+            let len = self.take_u32()?;
+            self.take(len)
+        }
+
+        fn take_spans(&mut self) -> Result<SourceMap, FormatError> {
+            let count = self.take_u32()?;
+            let mut spans = Vec::new();
+            for _ in 0..count {
+                let offset = self.take_u32()?;
+                let span = Span {
+                    start: self.take_u32()?,
+                    end: self.take_u32()?,
+                    line: self.take_u32()?,
+                    column: self.take_u32()?,
+                };
+                spans.push((offset, span));
+            }
+            Ok(spans)
+        }
+    }
+}
+
+pub mod script {
+    use super::{
+        ast::Program,
+        compiler::{compile, CompileError},
+        eval::eval_program,
+        lexer::{LexError, Lexer},
+        object::{Environment, Object, RuntimeError},
+        parser::{ParseError, Parser},
+        vm,
+    };
+    use std::cell::RefCell;
+    use std::fmt;
+    use std::rc::Rc;
+    use std::str::FromStr;
+
+    // How a program gets run: walking the tree, or compiled to bytecode
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    pub enum Engine {
+        Eval,
+        Vm,
+    }
+
+    impl FromStr for Engine {
+        type Err = String;
+
+        fn from_str(name: &str) -> Result<Engine, String> {
+            match name {
+                "eval" => Ok(Engine::Eval),
+                "vm" => Ok(Engine::Vm),
+                _ => Err(format!("unknown engine `{}`, expected eval or vm", name)),
+            }
+        }
+    }
+
+    #[derive(Debug, PartialEq, Clone)]
+    pub enum ScriptErrorKind {
+        Lex(LexError),
+        Parse(ParseError),
+        Compile(CompileError),
+        Runtime(RuntimeError),
+    }
+
+    // An error in a script, reported against the file it came
+    // from as `file:line:col: message`
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct ScriptError {
+        pub file: String,
+        pub kind: ScriptErrorKind,
+    }
+
+    impl fmt::Display for ScriptError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            match &self.kind {
+                ScriptErrorKind::Lex(err) => write!(f, "{}:{}", self.file, err),
+                ScriptErrorKind::Parse(err) => write!(f, "{}:{}", self.file, err),
+                ScriptErrorKind::Compile(err) => write!(f, "{}:{}", self.file, err),
+                ScriptErrorKind::Runtime(err) if err.span.is_some() => {
+                    write!(f, "{}:{}", self.file, err)
+                }
+                ScriptErrorKind::Runtime(err) => write!(f, "{}: {}", self.file, err),
+            }
+        }
+    }
+
+    impl std::error::Error for ScriptError {}
+
+    // Parses a whole script, handing back every error rather than just
+    // the first. file names the script in errors.
+    pub fn parse(file: &str, source: &str) -> Result<Program, Vec<ScriptError>> {
+        let mut lexer = Lexer::new(source.to_string());
+        let mut parser = Parser::new(&mut lexer);
+        let program = parser.parse_program();
+        let parse_errors = parser.errors().to_vec();
+
+        // What the lexer found comes first, since a bad token is
+        // usually what the parse errors after it trip over
+        let errors: Vec<ScriptError> = lexer
+            .diagnostics()
+            .iter()
+            .map(|err| script_error(file, ScriptErrorKind::Lex(err.clone())))
+            .chain(
+                parse_errors
+                    .into_iter()
+                    .map(|err| script_error(file, ScriptErrorKind::Parse(err))),
+            )
+            .collect();
+        if errors.is_empty() {
+            return Ok(program);
+        }
+        Err(errors)
+    }
+
+    // Parses and evaluates a whole script. Nothing is
+    // evaluated unless the whole script parses.
+    pub fn run(file: &str, source: &str) -> Result<Object, Vec<ScriptError>> {
+        run_with(Engine::Eval, file, source)
+    }
+
+    pub fn run_with(engine: Engine, file: &str, source: &str) -> Result<Object, Vec<ScriptError>> {
+        let program = parse(file, source)?;
+
+        let result = match engine {
+            Engine::Eval => {
+                let env = Rc::new(RefCell::new(Environment::new()));
+                match eval_program(&program, &env) {
+                    Object::Error(err) => Err(ScriptErrorKind::Runtime(err)),
+                    value => Ok(value),
+                }
+            }
+            Engine::Vm => match compile(&program) {
+                Ok(bytecode) => vm::run(bytecode).map_err(ScriptErrorKind::Runtime),
+                Err(err) => Err(ScriptErrorKind::Compile(err)),
+            },
+        };
+        result.map_err(|kind| vec![script_error(file, kind)])
+    }
+
+    fn script_error(file: &str, kind: ScriptErrorKind) -> ScriptError {
+        ScriptError {
+            file: file.to_string(),
+            kind,
+        }
+    }
+}
+
+pub mod cli {
+    // Command line parsing for the monkey binary, by hand since
+    // the crate has no dependencies. Options may come before or after
+    // the command, and take their value as `--opt value` or `--opt=value`.
+    use super::script::Engine;
+    use std::fmt;
+
+    pub const HELP: &str = "\
+Usage: monkey [OPTIONS] [COMMAND] [FILE]
+
+Commands:
+  repl                   Start the REPL, the default when stdin is a terminal
+  run [FILE]             Run a script or a compiled .mkc program
+  tokens [FILE]          Print the tokens of a script
+  ast [FILE]             Print the syntax tree of a script
+  fmt [FILE]             Print a script in canonical form, if it has no comments
+  compile FILE           Compile a script to FILE with a .mkc extension
+  FILE                   Same as `run FILE`
+
+Commands read FILE, the code given with -e, or otherwise stdin. fmt can't
+keep comments yet, so it refuses a script with any rather than drop them.
+
+Options:
+  -e, --eval CODE        Use CODE as the script
+  -o, --output OUT       Where `compile` writes to
+      --engine ENGINE    Run with `eval`, the tree walker, or `vm` [default: eval]
+      --prompt PROMPT    The REPL prompt [default: >>]
+      --disassemble      Print the bytecode `run` would execute instead
+      --no-color         Don't color error messages
+  -V, --version          Print the version
+  -h, --help             Print this help
+";
+
+    #[derive(Debug, PartialEq, Clone, Copy)]
+    pub enum Command {
+        Repl,
+        Run,
+        Tokens,
+        Ast,
+        Fmt,
+        Compile,
+        Help,
+        Version,
+    }
+
+    // Where a command reads its script from
+    #[derive(Debug, PartialEq, Clone)]
+    pub enum Input {
+        Stdin,
+        File(String),
+        Code(String),
+    }
+
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct Options {
+        pub command: Command,
+        pub input: Input,
+        pub output: Option<String>,
+        pub engine: Engine,
+        pub prompt: String,
+        pub color: bool,
+        pub disassemble: bool,
+    }
+
+    // Bad arguments, with a message saying what was wrong with them
+    #[derive(Debug, PartialEq, Clone)]
+    pub struct UsageError(pub String);
+
+    impl fmt::Display for UsageError {
+        fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+            write!(f, "{}", self.0)
+        }
+    }
+
+    impl std::error::Error for UsageError {}
+
+    fn usage_error<T>(message: String) -> Result<T, UsageError> {
+        Err(UsageError(message))
+    }
+
+    // Parses the arguments after the program name. With no command, a
+    // script is run if one was given, and the REPL started otherwise,
+    // unless stdin isn't a terminal in which case it's run as a script.
+    pub fn parse_args<I>(args: I, stdin_is_terminal: bool) -> Result<Options, UsageError>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut command = None;
+        let mut file = None;
+        let mut code = None;
+        let mut options = Options {
+            command: Command::Repl,
+            input: Input::Stdin,
+            output: None,
+            engine: Engine::Eval,
+            prompt: String::from(">>"),
+            color: true,
+            disassemble: false,
+        };
+
+        let mut args = args.into_iter();
+        while let Some(arg) = args.next() {
+            // Split `--opt=value` so both spellings are handled alike
+            let (name, mut value) = match arg.find('=') {
+                Some(i) if arg.starts_with("--") => (&arg[..i], Some(arg[i + 1..].to_string())),
+                _ => (arg.as_str(), None),
+            };
+            let mut value_of = |name: &str| match value.take().or_else(|| args.next()) {
+                Some(value) => Ok(value),
+                None => usage_error(format!("{} needs a value", name)),
+            };
+
+            match name {
+                "-h" | "--help" => options.command = Command::Help,
+                "-V" | "--version" => options.command = Command::Version,
+                "-e" | "--eval" => code = Some(value_of(name)?),
+                "-o" | "--output" => options.output = Some(value_of(name)?),
+                "--prompt" => options.prompt = value_of(name)?,
+                "--engine" => options.engine = value_of(name)?.parse().map_err(UsageError)?,
+                "--no-color" => options.color = false,
+                "--disassemble" => options.disassemble = true,
+                "-" => set_file(&mut file, arg.clone())?,
+                _ if name.starts_with('-') => {
+                    return usage_error(format!("unknown option `{}`", name))
+                }
+                _ if command.is_none() && file.is_none() => {
+                    command = match name {
+                        "repl" => Some(Command::Repl),
+                        "run" => Some(Command::Run),
+                        "tokens" => Some(Command::Tokens),
+                        "ast" => Some(Command::Ast),
+                        "fmt" => Some(Command::Fmt),
+                        "compile" => Some(Command::Compile),
+                        "help" => Some(Command::Help),
+                        _ => {
+                            set_file(&mut file, arg.clone())?;
+                            Some(Command::Run)
+                        }
+                    }
+                }
+                _ => set_file(&mut file, arg.clone())?,
+            }
+            if value.is_some() {
+                return usage_error(format!("{} doesn't take a value", name));
+            }
+        }
+
+        // --help and --version win over anything else on the line
+        if let Command::Help | Command::Version = options.command {
+            return Ok(options);
+        }
+
+        options.command = match command {
+            Some(command) => command,
+            None if code.is_some() || options.disassemble || !stdin_is_terminal => Command::Run,
+            None => Command::Repl,
+        };
+        options.input = match (file, code) {
+            (Some(_), Some(_)) => return usage_error("give either FILE or -e, not both".into()),
+            (Some(file), None) if file == "-" => Input::Stdin,
+            (Some(file), None) => Input::File(file),
+            (None, Some(code)) => Input::Code(code),
+            (None, None) => Input::Stdin,
+        };
+
+        match options.command {
+            Command::Repl if options.input != Input::Stdin => {
+                usage_error("repl doesn't take a script".into())
+            }
+            Command::Compile if !matches!(options.input, Input::File(_)) => {
+                usage_error("compile needs a FILE".into())
+            }
+            command if options.output.is_some() && command != Command::Compile => {
+                usage_error("--output only applies to compile".into())
+            }
+            command if options.disassemble && command != Command::Run => {
+                usage_error("--disassemble only applies to run".into())
+            }
+            _ => Ok(options),
+        }
+    }
+
+    fn set_file(file: &mut Option<String>, arg: String) -> Result<(), UsageError> {
+        if let Some(first) = file {
+            return usage_error(format!("unexpected argument `{}` after `{}`", arg, first));
+        }
+        *file = Some(arg);
+        Ok(())
+    }
+}
+
+pub mod formatter {
+    // Prints programs back as source the way a person would write it:
+    // one statement per line, blocks indented, and only the parentheses
+    // that precedence actually needs. Display on the ast is the debugging
+    // form instead, with every expression in parentheses.
+    use super::{
+        ast::*,
+        lexer::Lexer,
+        parser::{precedence, Precedence},
+        token::{Span, TokenType},
+    };
+
+    const INDENT: &str = "    ";
+
+    pub fn format_program(program: &Program) -> String {
+        let mut out = String::new();
+        for stmt in program.statements.iter() {
+            out.push_str(&statement(stmt, 0));
+            out.push_str(";\n");
+        }
+        out
+    }
+
+    // Comments aren't in the syntax tree, so a formatted program would
+    // lose them. Where the first one is, for refusing to format.
+    pub fn first_comment(source: &str) -> Option<Span> {
+        Lexer::new(source)
+            .keep_comments(true)
+            .find(|tok| tok.kind == TokenType::COMMENT)
+            .map(|tok| tok.span)
+    }
+
+    fn statement(stmt: &Statement, indent: usize) -> String {
+        match stmt {
+            Statement::Let { name, value } => {
+                format!("let {} = {}", name, expression(value, indent))
+            }
+            Statement::Return(value) => format!("return {}", expression(value, indent)),
+            Statement::Expression(expr) => expression(expr, indent),
+        }
+    }
+
+    // A trailing expression statement is the block's value,
+    // so it's the one statement left without a semicolon
+    fn block(block: &BlockStatement, indent: usize) -> String {
+        if block.statements.is_empty() {
+            return String::from("{}");
+        }
+
+        let mut out = String::from("{\n");
+        for (i, stmt) in block.statements.iter().enumerate() {
+            out.push_str(&INDENT.repeat(indent + 1));
+            out.push_str(&statement(stmt, indent + 1));
+            match stmt {
+                Statement::Expression(_) if i == block.statements.len() - 1 => {}
+                _ => out.push(';'),
+            }
+            out.push('\n');
+        }
+        out.push_str(&INDENT.repeat(indent));
+        out.push('}');
+        out
+    }
+
+    // How tightly an expression holds together, as the parser sees it.
+    // Literals and the like can go anywhere without parentheses.
+    fn binding(expr: &Expression) -> Precedence {
+        match &expr.kind {
+            ExpressionKind::Infix { operator, .. } => precedence(operator),
+            ExpressionKind::Prefix { .. } => Precedence::Prefix,
+            ExpressionKind::Call { .. } => Precedence::Call,
+            _ => Precedence::Index,
+        }
+    }
+
+    fn expression(expr: &Expression, indent: usize) -> String {
+        let list = |items: &[Expression]| {
+            let items: Vec<String> = items.iter().map(|item| expression(item, indent)).collect();
+            items.join(", ")
+        };
+
+        match &expr.kind {
+            ExpressionKind::Prefix { operator, right } => {
+                // A prefix operand is parsed at prefix precedence, which
+                // another prefix operator or `**` on its own satisfies
+                let right = match right.kind {
+                    ExpressionKind::Prefix { .. } => expression(right, indent),
+                    _ => operand(right, Precedence::Prefix, indent, true),
+                };
+                format!("{}{}", operator.literal(), right)
+            }
+            ExpressionKind::Infix {
+                left,
+                operator,
+                right,
+            } => {
+                let op = precedence(operator);
+                // `**` is right associative and takes a prefix expression
+                // on its right, where the others are left associative
+                let (left, right) = match operator {
+                    TokenType::POWER => (
+                        operand(left, op, indent, true),
+                        operand(right, Precedence::Prefix, indent, false),
+                    ),
+                    _ => (
+                        operand(left, op, indent, false),
+                        operand(right, op, indent, true),
+                    ),
+                };
+                format!("{} {} {}", left, operator.literal(), right)
+            }
+            ExpressionKind::If {
+                condition,
+                consequence,
+                alternative,
+            } => {
+                let mut out = format!(
+                    "if ({}) {}",
+                    expression(condition, indent),
+                    block(consequence, indent)
+                );
+                if let Some(alternative) = alternative {
+                    out.push_str(" else ");
+                    out.push_str(&block(alternative, indent));
+                }
+                out
+            }
+            ExpressionKind::Function { parameters, body } => {
+                let parameters: Vec<String> = parameters.iter().map(|p| p.to_string()).collect();
+                format!("fn({}) {}", parameters.join(", "), block(body, indent))
+            }
+            ExpressionKind::Call {
+                function,
+                arguments,
+            } => format!(
+                "{}({})",
+                operand(function, Precedence::Call, indent, false),
+                list(arguments)
+            ),
+            ExpressionKind::Index { left, index } => format!(
+                "{}[{}]",
+                operand(left, Precedence::Call, indent, false),
+                expression(index, indent)
+            ),
+            ExpressionKind::Array(elements) => format!("[{}]", list(elements)),
+            ExpressionKind::Hash(pairs) => {
+                let pairs: Vec<String> = pairs
+                    .iter()
+                    .map(|(key, value)| {
+                        format!("{}: {}", expression(key, indent), expression(value, indent))
+                    })
+                    .collect();
+                format!("{{{}}}", pairs.join(", "))
+            }
+            // Identifiers and literals print the same either way
+            _ => expr.to_string(),
+        }
+    }
+
+    // An operand of an operator that binds as tightly as context, in
+    // parentheses if it would otherwise come apart. When strict, an
+    // operand that binds just as tightly needs them too.
+    fn operand(expr: &Expression, context: Precedence, indent: usize, strict: bool) -> String {
+        let binding = binding(expr);
+        let formatted = expression(expr, indent);
+        if binding < context || (strict && binding == context) {
+            format!("({})", formatted)
+        } else {
+            formatted
+        }
     }
 }
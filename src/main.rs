@@ -1,11 +1,206 @@
-use monkey::repl::Repl;
+use monkey::cli::{self, Command, Input, Options};
+use monkey::compiler::{self, Bytecode};
+use monkey::lexer::diagnose;
+use monkey::repl::{self, Repl};
+use monkey::script::{self, ScriptError};
+use monkey::{eval, formatter, mkc, vm};
+use std::env;
+use std::fmt::Display;
+use std::fs;
+use std::io::{self, IsTerminal, Read, Write};
+use std::path::Path;
 use std::process;
+use std::thread;
+
+// Deeply recursive scripts need far more native stack than the main
+// thread has to reach the evaluator's call depth limit. The memory is
+// only used as the stack actually grows.
+const STACK_SIZE: usize = 256 << 20;
+// What evaluation may use of it, leaving room for everything else
+const STACK_BUDGET: usize = STACK_SIZE / 2;
 
 fn main() {
-    let repl = Repl::new(String::from(">>"));
+    let cli = thread::Builder::new()
+        .stack_size(STACK_SIZE)
+        .spawn(run_cli)
+        .unwrap_or_else(|err| fail(false, err));
+    if cli.join().is_err() {
+        process::exit(101);
+    }
+}
+
+fn run_cli() {
+    eval::set_stack_budget(STACK_BUDGET);
+    let options = match cli::parse_args(env::args().skip(1), io::stdin().is_terminal()) {
+        Ok(options) => options,
+        Err(err) => {
+            report(color_enabled(true), err);
+            eprintln!("Run `monkey --help` for usage.");
+            process::exit(2);
+        }
+    };
+    let color = color_enabled(options.color);
+
+    match options.command {
+        Command::Help => print_out(cli::HELP),
+        Command::Version => print_out(&format!("monkey {}\n", env!("CARGO_PKG_VERSION"))),
+        Command::Repl => {
+            let repl = Repl::new(options.prompt.clone())
+                .engine(options.engine)
+                .color(color);
+
+            if let Err(e) = repl.run() {
+                eprint!("Error in REPL: {}", e);
+                process::exit(1);
+            }
+        }
+        Command::Run => run(&options, color),
+        Command::Tokens => {
+            let (_, source) = read_source(&options.input, color);
+            let printed = repl::print_tokens(&source, &mut io::stdout().lock());
+            printed.unwrap_or_else(|err| output_failed(err));
+            if !diagnose(&source).is_empty() {
+                process::exit(1);
+            }
+        }
+        Command::Ast => {
+            let (file, source) = read_source(&options.input, color);
+            let program =
+                script::parse(&file, &source).unwrap_or_else(|errors| fail_script(color, errors));
+            print_out(&format!("{:#?}\n", program));
+        }
+        Command::Fmt => {
+            let (file, source) = read_source(&options.input, color);
+            let program =
+                script::parse(&file, &source).unwrap_or_else(|errors| fail_script(color, errors));
+            if let Some(span) = formatter::first_comment(&source) {
+                fail(
+                    color,
+                    format!(
+                        "{}:{}: fmt would drop this comment, so it leaves the script alone",
+                        file, span
+                    ),
+                );
+            }
+            print_out(&formatter::format_program(&program));
+        }
+        Command::Compile => compile(&options, color),
+    }
+}
+
+// Runs a compiled program on the vm, or anything else as a script
+fn run(options: &Options, color: bool) {
+    let (file, bytecode) = match &options.input {
+        Input::File(path) if is_compiled(path) => (path.clone(), load(path, color)),
+        input => {
+            let (file, source) = read_source(input, color);
+            if !options.disassemble {
+                if let Err(errors) = script::run_with(options.engine, &file, &source) {
+                    fail_script(color, errors);
+                }
+                return;
+            }
+            let bytecode = compile_source(&file, &source, color);
+            (file, bytecode)
+        }
+    };
+
+    if options.disassemble {
+        print_out(&compiler::disassemble(&bytecode));
+    } else if let Err(err) = vm::run(bytecode) {
+        match err.span {
+            Some(_) => fail(color, format!("{}:{}", file, err)),
+            None => fail(color, format!("{}: {}", file, err)),
+        }
+    }
+}
+
+fn compile(options: &Options, color: bool) {
+    let (file, source) = read_source(&options.input, color);
+    let out = match &options.output {
+        Some(out) => Path::new(out).to_path_buf(),
+        None => Path::new(&file).with_extension("mkc"),
+    };
+
+    let bytecode = compile_source(&file, &source, color);
+    let bytes = mkc::encode(&bytecode).unwrap_or_else(|err| fail(color, err));
+    fs::write(&out, bytes).unwrap_or_else(|err| fail(color, format!("{}: {}", out.display(), err)));
+}
+
+fn is_compiled(path: &str) -> bool {
+    let mut magic = [0; 4];
+    fs::File::open(path)
+        .and_then(|mut file| file.read_exact(&mut magic))
+        .map(|_| &magic == mkc::MAGIC)
+        .unwrap_or(false)
+}
+
+fn load(path: &str, color: bool) -> Bytecode {
+    let bytes = fs::read(path).unwrap_or_else(|err| fail(color, format!("{}: {}", path, err)));
+    mkc::decode(&bytes).unwrap_or_else(|err| fail(color, format!("{}: {}", path, err)))
+}
+
+// Reads a script, along with the name to give it in errors
+fn read_source(input: &Input, color: bool) -> (String, String) {
+    let (file, source) = match input {
+        Input::File(path) => (path.clone(), fs::read_to_string(path)),
+        Input::Code(code) => (String::from("-e"), Ok(code.clone())),
+        Input::Stdin => {
+            let mut source = String::new();
+            let read = io::stdin().read_to_string(&mut source).map(|_| source);
+            (String::from("<stdin>"), read)
+        }
+    };
+    match source {
+        Ok(source) => (file, source),
+        Err(err) => fail(color, format!("{}: {}", file, err)),
+    }
+}
+
+fn compile_source(file: &str, source: &str, color: bool) -> Bytecode {
+    let program = script::parse(file, source).unwrap_or_else(|errors| fail_script(color, errors));
+    compiler::compile(&program).unwrap_or_else(|err| fail(color, format!("{}:{}", file, err)))
+}
+
+// Writes a command's output to stdout
+fn print_out(text: &str) {
+    let mut stdout = io::stdout().lock();
+    let written = stdout
+        .write_all(text.as_bytes())
+        .and_then(|_| stdout.flush());
+    written.unwrap_or_else(|err| output_failed(err));
+}
+
+// Output piped into something like `head` that stops reading is
+// closed early, which is a normal way for the command to end
+fn output_failed(err: io::Error) -> ! {
+    if err.kind() == io::ErrorKind::BrokenPipe {
+        process::exit(0);
+    }
+    fail(false, err)
+}
+
+// Color is only used on a terminal, and never when NO_COLOR is set
+fn color_enabled(wanted: bool) -> bool {
+    wanted && io::stderr().is_terminal() && env::var_os("NO_COLOR").is_none()
+}
+
+fn report<E: Display>(color: bool, err: E) {
+    if color {
+        eprintln!("\x1b[1;31merror\x1b[0m: {}", err);
+    } else {
+        eprintln!("error: {}", err);
+    }
+}
+
+fn fail<E: Display>(color: bool, err: E) -> ! {
+    report(color, err);
+    process::exit(1);
+}
 
-    if let Err(e) = repl.run() {
-        eprint!("Error in REPL: {}", e);
-        process::exit(1);
+fn fail_script(color: bool, errors: Vec<ScriptError>) -> ! {
+    for error in errors.iter() {
+        report(color, error);
     }
+    process::exit(1);
 }
@@ -0,0 +1,105 @@
+use monkey::{
+    cli::{parse_args, Command, Input, Options, UsageError},
+    script::Engine,
+};
+
+fn parse(args: &str) -> Result<Options, UsageError> {
+    parse_args(args.split_whitespace().map(String::from), true)
+}
+
+#[test]
+fn test_defaults() {
+    assert_eq!(
+        parse("").unwrap(),
+        Options {
+            command: Command::Repl,
+            input: Input::Stdin,
+            output: None,
+            engine: Engine::Eval,
+            prompt: String::from(">>"),
+            color: true,
+            disassemble: false,
+        }
+    );
+
+    // Piped input is run rather than read by the REPL
+    let options = parse_args(Vec::new(), false).unwrap();
+    assert_eq!(
+        (options.command, options.input),
+        (Command::Run, Input::Stdin)
+    );
+}
+
+#[test]
+fn test_commands_and_inputs() {
+    let tests = vec![
+        ("repl", Command::Repl, Input::Stdin),
+        (
+            "run script.mk",
+            Command::Run,
+            Input::File("script.mk".into()),
+        ),
+        ("script.mk", Command::Run, Input::File("script.mk".into())),
+        ("tokens", Command::Tokens, Input::Stdin),
+        ("ast -", Command::Ast, Input::Stdin),
+        ("fmt -e 1+2", Command::Fmt, Input::Code("1+2".into())),
+        ("-e 1+2", Command::Run, Input::Code("1+2".into())),
+        ("compile a.mk", Command::Compile, Input::File("a.mk".into())),
+        (
+            "--disassemble a.mk",
+            Command::Run,
+            Input::File("a.mk".into()),
+        ),
+    ];
+
+    for (args, command, input) in tests {
+        let options = parse(args).unwrap();
+        assert_eq!(
+            (options.command, options.input),
+            (command, input),
+            "args: {}",
+            args
+        );
+    }
+}
+
+#[test]
+fn test_options() {
+    let options = parse("--engine=vm repl --prompt m> --no-color").unwrap();
+    assert_eq!(options.engine, Engine::Vm);
+    assert_eq!(options.prompt, "m>");
+    assert!(!options.color);
+
+    let options = parse("compile a.mk --output b.mkc").unwrap();
+    assert_eq!(options.output, Some("b.mkc".into()));
+
+    // Help and version take over whatever else was asked for
+    assert_eq!(parse("run a.mk --help").unwrap().command, Command::Help);
+    assert_eq!(parse("-V").unwrap().command, Command::Version);
+    assert_eq!(parse("help").unwrap().command, Command::Help);
+}
+
+#[test]
+fn test_usage_errors() {
+    let tests = vec![
+        ("--engine=js", "unknown engine `js`, expected eval or vm"),
+        ("--engine", "--engine needs a value"),
+        ("--frobnicate", "unknown option `--frobnicate`"),
+        ("--no-color=yes", "--no-color doesn't take a value"),
+        ("run a.mk b.mk", "unexpected argument `b.mk` after `a.mk`"),
+        ("run a.mk -e 1", "give either FILE or -e, not both"),
+        ("repl a.mk", "repl doesn't take a script"),
+        ("compile -e 1", "compile needs a FILE"),
+        ("run a.mk -o b", "--output only applies to compile"),
+        ("fmt --disassemble", "--disassemble only applies to run"),
+    ];
+
+    for (args, message) in tests {
+        assert_eq!(
+            parse(args),
+            Err(UsageError(message.into())),
+            "args: {}",
+            args
+        );
+    }
+}
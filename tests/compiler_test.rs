@@ -0,0 +1,423 @@
+use monkey::{
+    code::{make, read_operands, Instructions, Opcode},
+    compiler::{compile, disassemble, Bytecode, CompileError, SymbolScope, SymbolTable},
+    object::Object,
+    parser::parse,
+};
+
+fn compile_input(input: &str) -> Result<Bytecode, CompileError> {
+    let (program, errors) = parse(input);
+    assert!(errors.is_empty(), "parser had errors: {:#?}", errors);
+    compile(&program)
+}
+
+fn concat(instructions: Vec<Vec<u8>>) -> Instructions {
+    Instructions(instructions.concat())
+}
+
+// The instructions of the compiled function stored in a constant
+fn function_instructions(constant: &Object) -> Instructions {
+    match constant {
+        Object::CompiledFunction(function) => function.instructions.clone(),
+        other => panic!("expected a compiled function, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_make() {
+    let tests = vec![
+        (
+            Opcode::Constant,
+            vec![65534],
+            vec![Opcode::Constant as u8, 255, 254],
+        ),
+        (Opcode::Add, vec![], vec![Opcode::Add as u8]),
+        (
+            Opcode::GetLocal,
+            vec![255],
+            vec![Opcode::GetLocal as u8, 255],
+        ),
+        (
+            Opcode::Closure,
+            vec![65534, 255],
+            vec![Opcode::Closure as u8, 255, 254, 255],
+        ),
+    ];
+
+    for (op, operands, expected) in tests {
+        assert_eq!(make(op, &operands), expected, "op: {:?}", op);
+    }
+}
+
+#[test]
+fn test_read_operands() {
+    let tests = vec![
+        (Opcode::Constant, vec![65535], 2),
+        (Opcode::GetLocal, vec![255], 1),
+        (Opcode::Closure, vec![65535, 255], 3),
+    ];
+
+    for (op, operands, bytes_read) in tests {
+        let instruction = make(op, &operands);
+        let definition = op.definition();
+        let (read, n) = read_operands(&definition, &instruction[1..]);
+        assert_eq!(n, bytes_read);
+        assert_eq!(read, operands);
+    }
+}
+
+#[test]
+fn test_instructions_string() {
+    let instructions = concat(vec![
+        make(Opcode::Add, &[]),
+        make(Opcode::GetLocal, &[1]),
+        make(Opcode::Constant, &[2]),
+        make(Opcode::Constant, &[65535]),
+        make(Opcode::Closure, &[65535, 255]),
+    ]);
+    let expected = "0000 OpAdd
+0001 OpGetLocal 1
+0003 OpConstant 2
+0006 OpConstant 65535
+0009 OpClosure 65535 255
+";
+    assert_eq!(instructions.to_string(), expected);
+
+    // Bad bytecode is shown rather than panicking
+    let instructions = Instructions(vec![Opcode::Pop as u8, 255, Opcode::Constant as u8, 1]);
+    let expected = "0000 OpPop
+0001 ERROR: unknown opcode 255
+0002 ERROR: OpConstant is cut short
+";
+    assert_eq!(instructions.to_string(), expected);
+}
+
+#[test]
+fn test_opcode_from_byte() {
+    for op in &[
+        Opcode::Constant,
+        Opcode::Pop,
+        Opcode::Closure,
+        Opcode::Hash,
+        Opcode::GetGlobalChecked,
+    ] {
+        assert_eq!(Opcode::from_byte(*op as u8), Some(*op));
+    }
+    assert_eq!(Opcode::from_byte(Opcode::GetGlobalChecked as u8 + 1), None);
+}
+
+#[test]
+fn test_integer_arithmetic() {
+    let bytecode = compile_input("1 + 2; 3 ** 2").unwrap();
+    assert_eq!(
+        bytecode.instructions,
+        concat(vec![
+            make(Opcode::Constant, &[0]),
+            make(Opcode::Constant, &[1]),
+            make(Opcode::Add, &[]),
+            make(Opcode::Pop, &[]),
+            make(Opcode::Constant, &[2]),
+            make(Opcode::Constant, &[3]),
+            make(Opcode::Pow, &[]),
+            make(Opcode::Pop, &[]),
+        ])
+    );
+    assert_eq!(
+        bytecode.constants,
+        vec![
+            Object::Integer(1),
+            Object::Integer(2),
+            Object::Integer(3),
+            Object::Integer(2)
+        ]
+    );
+}
+
+#[test]
+fn test_conditionals() {
+    let bytecode = compile_input("if (true) { 10 }; 3333;").unwrap();
+    assert_eq!(
+        bytecode.instructions,
+        concat(vec![
+            make(Opcode::True, &[]),            // 0000
+            make(Opcode::JumpNotTruthy, &[10]), // 0001
+            make(Opcode::Constant, &[0]),       // 0004
+            make(Opcode::Jump, &[11]),          // 0007
+            make(Opcode::Null, &[]),            // 0010
+            make(Opcode::Pop, &[]),             // 0011
+            make(Opcode::Constant, &[1]),       // 0012
+            make(Opcode::Pop, &[]),             // 0015
+        ])
+    );
+}
+
+#[test]
+fn test_logical_operators() {
+    let bytecode = compile_input("true && false").unwrap();
+    assert_eq!(
+        bytecode.instructions,
+        concat(vec![
+            make(Opcode::True, &[]),            // 0000
+            make(Opcode::JumpNotTruthy, &[10]), // 0001
+            make(Opcode::False, &[]),           // 0004
+            make(Opcode::Bang, &[]),            // 0005
+            make(Opcode::Bang, &[]),            // 0006
+            make(Opcode::Jump, &[11]),          // 0007
+            make(Opcode::False, &[]),           // 0010
+            make(Opcode::Pop, &[]),             // 0011
+        ])
+    );
+
+    let bytecode = compile_input("false || true").unwrap();
+    assert_eq!(
+        bytecode.instructions,
+        concat(vec![
+            make(Opcode::False, &[]),          // 0000
+            make(Opcode::JumpNotTruthy, &[8]), // 0001
+            make(Opcode::True, &[]),           // 0004
+            make(Opcode::Jump, &[11]),         // 0005
+            make(Opcode::True, &[]),           // 0008
+            make(Opcode::Bang, &[]),           // 0009
+            make(Opcode::Bang, &[]),           // 0010
+            make(Opcode::Pop, &[]),            // 0011
+        ])
+    );
+}
+
+#[test]
+fn test_global_let_statements() {
+    let bytecode = compile_input("let one = 1; let two = one; two;").unwrap();
+    assert_eq!(
+        bytecode.instructions,
+        concat(vec![
+            make(Opcode::Constant, &[0]),
+            make(Opcode::SetGlobal, &[0]),
+            make(Opcode::GetGlobal, &[0]),
+            make(Opcode::SetGlobal, &[1]),
+            make(Opcode::GetGlobal, &[1]),
+            make(Opcode::Pop, &[]),
+        ])
+    );
+}
+
+#[test]
+fn test_functions() {
+    let bytecode = compile_input("fn() { return 5 + 10 }").unwrap();
+    assert_eq!(
+        bytecode.instructions,
+        concat(vec![make(Opcode::Closure, &[2, 0]), make(Opcode::Pop, &[])])
+    );
+    assert_eq!(
+        function_instructions(&bytecode.constants[2]),
+        concat(vec![
+            make(Opcode::Constant, &[0]),
+            make(Opcode::Constant, &[1]),
+            make(Opcode::Add, &[]),
+            make(Opcode::ReturnValue, &[]),
+        ])
+    );
+
+    // An empty body returns null
+    let bytecode = compile_input("fn() { }").unwrap();
+    assert_eq!(
+        function_instructions(&bytecode.constants[0]),
+        concat(vec![make(Opcode::Return, &[])])
+    );
+}
+
+#[test]
+fn test_closures() {
+    let bytecode = compile_input("fn(a) { fn(b) { a + b } }").unwrap();
+    assert_eq!(
+        function_instructions(&bytecode.constants[0]),
+        concat(vec![
+            make(Opcode::GetFree, &[0]),
+            make(Opcode::GetLocal, &[0]),
+            make(Opcode::Add, &[]),
+            make(Opcode::ReturnValue, &[]),
+        ])
+    );
+    assert_eq!(
+        function_instructions(&bytecode.constants[1]),
+        concat(vec![
+            make(Opcode::GetLocal, &[0]),
+            make(Opcode::Closure, &[0, 1]),
+            make(Opcode::ReturnValue, &[]),
+        ])
+    );
+}
+
+#[test]
+fn test_recursive_functions() {
+    let bytecode = compile_input("let countdown = fn(x) { countdown(x - 1) };").unwrap();
+    assert_eq!(
+        function_instructions(&bytecode.constants[1]),
+        concat(vec![
+            make(Opcode::CurrentClosure, &[]),
+            make(Opcode::GetLocal, &[0]),
+            make(Opcode::Constant, &[0]),
+            make(Opcode::Sub, &[]),
+            make(Opcode::Call, &[1]),
+            make(Opcode::ReturnValue, &[]),
+        ])
+    );
+}
+
+#[test]
+fn test_builtins() {
+    let bytecode = compile_input("len([]);").unwrap();
+    assert_eq!(
+        bytecode.instructions,
+        concat(vec![
+            make(Opcode::GetBuiltin, &[0]),
+            make(Opcode::Array, &[0]),
+            make(Opcode::Call, &[1]),
+            make(Opcode::Pop, &[]),
+        ])
+    );
+}
+
+#[test]
+fn test_resolve_free_symbols() {
+    let mut global = SymbolTable::new();
+    global.define("a");
+    let mut first = SymbolTable::new_enclosed(global);
+    first.define("c");
+    let mut second = SymbolTable::new_enclosed(first);
+    second.define("e");
+
+    let expected = vec![
+        ("a", SymbolScope::Global, 0),
+        ("c", SymbolScope::Free, 0),
+        ("e", SymbolScope::Local, 0),
+    ];
+    for (name, scope, index) in expected {
+        let symbol = second.resolve(name).unwrap();
+        assert_eq!(
+            (symbol.scope, symbol.index),
+            (scope, index),
+            "name: {}",
+            name
+        );
+    }
+    assert_eq!(second.free_symbols.len(), 1);
+    assert_eq!(second.resolve("nope"), None);
+}
+
+#[test]
+fn test_unbound_identifiers() {
+    // g isn't bound yet when f is compiled, so the vm checks for it
+    let bytecode = compile_input("let f = fn() { g };\nlet g = 1;\ng").unwrap();
+    assert_eq!(
+        bytecode.instructions,
+        concat(vec![
+            make(Opcode::Closure, &[1, 0]),
+            make(Opcode::SetGlobal, &[1]),
+            make(Opcode::Constant, &[2]),
+            make(Opcode::SetGlobal, &[0]),
+            make(Opcode::GetGlobal, &[0]),
+            make(Opcode::Pop, &[]),
+        ])
+    );
+    assert_eq!(bytecode.constants[0], Object::String("g".to_string()));
+    assert_eq!(
+        function_instructions(&bytecode.constants[1]),
+        concat(vec![
+            make(Opcode::GetGlobalChecked, &[0, 0]),
+            make(Opcode::ReturnValue, &[]),
+        ])
+    );
+}
+
+#[test]
+fn test_disassemble() {
+    let bytecode = compile_input("let add = fn(a) { fn(b) { a + b } }; add(1)(\"x\")").unwrap();
+    let expected = "== main ==
+0000 OpClosure 1 0
+0004 OpSetGlobal 0
+0007 OpGetGlobal 0
+0010 OpConstant 2
+0013 OpCall 1
+0015 OpConstant 3
+0018 OpCall 1
+0020 OpPop
+== constants ==
+0000 COMPILED_FUNCTION
+0001 COMPILED_FUNCTION
+0002 INTEGER 1
+0003 STRING \"x\"
+== function 0 (parameters: 1, locals: 1) ==
+0000 OpGetFree 0
+0002 OpGetLocal 0
+0004 OpAdd
+0005 OpReturnValue
+== function 1 (parameters: 1, locals: 1) ==
+0000 OpGetLocal 0
+0002 OpClosure 0 1
+0006 OpReturnValue
+";
+    assert_eq!(disassemble(&bytecode), expected);
+}
+
+// Repeats item count times, with {} replaced by the index
+fn repeat(item: &str, count: usize, sep: &str) -> String {
+    (0..count)
+        .map(|i| item.replace("{}", &i.to_string()))
+        .collect::<Vec<_>>()
+        .join(sep)
+}
+
+#[test]
+fn test_operand_limits() {
+    let tests = vec![
+        (
+            repeat("{};", 65537, " "),
+            "too many constants, the limit is 65536",
+        ),
+        (
+            repeat("let g{} = true;", 65537, " "),
+            "too many global bindings, the limit is 65536",
+        ),
+        (
+            format!("fn() {{ {} }}", repeat("let v{} = true;", 257, " ")),
+            "too many local bindings in one function, the limit is 256",
+        ),
+        (
+            format!(
+                "fn({}) {{ fn() {{ [{}] }} }}",
+                repeat("a{}", 256, ", "),
+                repeat("a{}", 256, ", ")
+            ),
+            "too many free variables in one function, the limit is 255",
+        ),
+        (
+            format!("len({})", repeat("true", 256, ", ")),
+            "too many arguments in one call, the limit is 255",
+        ),
+        (
+            format!("[{}]", repeat("true", 65536, ", ")),
+            "too many elements in an array literal, the limit is 65535",
+        ),
+        (
+            format!("{{{}}}", repeat("true: true", 32768, ", ")),
+            "too many pairs in a hash literal, the limit is 32767",
+        ),
+        (
+            format!("if (true) {{ {} }}", repeat("true;", 33000, " ")),
+            "too much code in one function to jump across, the limit is 65535 bytes",
+        ),
+    ];
+
+    for (input, message) in tests {
+        let err = compile_input(&input).unwrap_err();
+        assert_eq!(err.message, message);
+    }
+
+    // Right at the limits still compiles
+    assert!(compile_input(&format!("len({})", repeat("true", 255, ", "))).is_ok());
+    assert!(compile_input(&format!(
+        "fn() {{ {} }}",
+        repeat("let v{} = true;", 256, " ")
+    ))
+    .is_ok());
+}
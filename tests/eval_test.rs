@@ -0,0 +1,624 @@
+use monkey::{
+    eval::{eval_program, set_stack_budget, MAX_CALL_DEPTH},
+    object::{Environment, Object, RuntimeError},
+    parser::parse,
+};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+// Evaluates input, dropping the location of any error so
+// that tests can compare against just the message
+fn eval(input: &str) -> Object {
+    match eval_with_spans(input) {
+        Object::Error(err) => Object::Error(RuntimeError::new(err.message)),
+        value => value,
+    }
+}
+
+fn eval_with_spans(input: &str) -> Object {
+    let (program, errors) = parse(input);
+    assert!(errors.is_empty(), "parser had errors: {:#?}", errors);
+
+    let env = Rc::new(RefCell::new(Environment::new()));
+    eval_program(&program, &env)
+}
+
+// An error without a location, as eval hands them back
+fn error(message: &str) -> Object {
+    Object::Error(RuntimeError::new(message.to_string()))
+}
+
+#[test]
+fn test_eval_integer_expression() {
+    let tests = vec![
+        ("5", 5),
+        ("-10", -10),
+        ("5 + 5 + 5 + 5 - 10", 10),
+        ("2 * 2 * 2 * 2 * 2", 32),
+        ("-50 + 100 + -50", 0),
+        ("20 + 2 * -10", 0),
+        ("50 / 2 * 2 + 10", 60),
+        ("3 * (3 * 3) + 10", 37),
+        ("(5 + 10 * 2 + 15 / 3) * 2 + -10", 50),
+    ];
+
+    for (input, expected) in tests {
+        assert_eq!(eval(input), Object::Integer(expected), "input: {}", input);
+    }
+}
+
+#[test]
+fn test_eval_boolean_expression() {
+    let tests = vec![
+        ("true", true),
+        ("1 < 2", true),
+        ("1 > 2", false),
+        ("1 == 1", true),
+        ("1 != 1", false),
+        ("true == true", true),
+        ("true != false", true),
+        ("(1 < 2) == true", true),
+        ("(1 > 2) == true", false),
+        ("!true", false),
+        ("!5", false),
+        ("!!5", true),
+    ];
+
+    for (input, expected) in tests {
+        assert_eq!(eval(input), Object::Boolean(expected), "input: {}", input);
+    }
+}
+
+#[test]
+fn test_if_else_expressions() {
+    let tests = vec![
+        ("if (true) { 10 }", Object::Integer(10)),
+        ("if (false) { 10 }", Object::Null),
+        ("if (1) { 10 }", Object::Integer(10)),
+        ("if (1 > 2) { 10 } else { 20 }", Object::Integer(20)),
+        (
+            "if (if (false) { 1 }) { 10 } else { 20 }",
+            Object::Integer(20),
+        ),
+    ];
+
+    for (input, expected) in tests {
+        assert_eq!(eval(input), expected, "input: {}", input);
+    }
+}
+
+#[test]
+fn test_return_statements() {
+    let tests = vec![
+        ("return 10; 9;", 10),
+        ("9; return 2 * 5; 9;", 10),
+        (
+            "if (10 > 1) {
+                if (10 > 1) {
+                    return 10;
+                }
+                return 1;
+            }",
+            10,
+        ),
+        ("let f = fn(x) { return x; x + 10; }; f(10);", 10),
+        (
+            "let f = fn(x) { if (x > 5) { return 1; } 2 }; f(10) + f(1);",
+            3,
+        ),
+    ];
+
+    for (input, expected) in tests {
+        assert_eq!(eval(input), Object::Integer(expected), "input: {}", input);
+    }
+}
+
+#[test]
+fn test_error_handling() {
+    let tests = vec![
+        ("5 + true;", "type mismatch: INTEGER + BOOLEAN"),
+        ("5 + true; 5;", "type mismatch: INTEGER + BOOLEAN"),
+        ("-true", "unknown operator: -BOOLEAN"),
+        ("true + false;", "unknown operator: BOOLEAN + BOOLEAN"),
+        ("5; true > false; 5", "unknown operator: BOOLEAN > BOOLEAN"),
+        (
+            "if (10 > 1) { if (10 > 1) { return true + false; } return 1; }",
+            "unknown operator: BOOLEAN + BOOLEAN",
+        ),
+        ("foobar", "identifier not found: foobar"),
+        ("10 / (5 - 5)", "division by zero"),
+        (
+            "9223372036854775807 + 1",
+            "integer overflow: 9223372036854775807 + 1",
+        ),
+        ("5(1)", "not a function: INTEGER"),
+        (
+            "fn(x, y) { x }(1)",
+            "wrong number of arguments: want=2, got=1",
+        ),
+    ];
+
+    for (input, expected) in tests {
+        assert_eq!(eval(input), error(expected), "input: {}", input);
+    }
+}
+
+#[test]
+fn test_function_application() {
+    let tests = vec![
+        ("let identity = fn(x) { x; }; identity(5);", 5),
+        ("let double = fn(x) { x * 2; }; double(5);", 10),
+        ("let add = fn(x, y) { x + y; }; add(5 + 5, add(5, 5));", 20),
+        ("fn(x) { x; }(5)", 5),
+    ];
+
+    for (input, expected) in tests {
+        assert_eq!(eval(input), Object::Integer(expected), "input: {}", input);
+    }
+}
+
+#[test]
+fn test_function_object_inspect() {
+    let function = eval("fn(x) { x + 2; };");
+
+    assert_eq!(function.type_name(), "FUNCTION");
+    assert_eq!(function.to_string(), "fn(x) { (x + 2); }");
+}
+
+#[test]
+fn test_let_statements() {
+    let tests = vec![
+        ("let a = 5; a;", 5),
+        ("let a = 5 * 5; a;", 25),
+        ("let a = 5; let b = a; b;", 5),
+        ("let a = 5; let b = a; let c = a + b + 5; c;", 15),
+    ];
+
+    for (input, expected) in tests {
+        assert_eq!(eval(input), Object::Integer(expected), "input: {}", input);
+    }
+}
+
+#[test]
+fn test_closures() {
+    let tests = vec![
+        (
+            "let adder = fn(x) { fn(y) { x + y } }; let addTwo = adder(2); addTwo(3);",
+            5,
+        ),
+        // functions see bindings from the scope they were defined in
+        ("let n = 10; let f = fn() { n }; f();", 10),
+        // recursion goes through the defining scope as well
+        (
+            "let fact = fn(n) { if (n < 2) { 1 } else { n * fact(n - 1) } }; fact(5);",
+            120,
+        ),
+        (
+            "let compose = fn(f, g) { fn(x) { g(f(x)) } };
+             let inc = fn(x) { x + 1 };
+             let double = fn(x) { x * 2 };
+             compose(inc, double)(4);",
+            10,
+        ),
+    ];
+
+    for (input, expected) in tests {
+        assert_eq!(eval(input), Object::Integer(expected), "input: {}", input);
+    }
+}
+
+#[test]
+fn test_function_scope_does_not_leak() {
+    // parameters and locals shadow outer bindings without overwriting them
+    assert_eq!(
+        eval("let x = 1; let f = fn(x) { let y = x * 10; y }; f(5) + x;"),
+        Object::Integer(51)
+    );
+    assert_eq!(
+        eval("let f = fn() { let local = 1; local }; f(); local;"),
+        error("identifier not found: local")
+    );
+}
+
+#[test]
+fn test_environment_lookup_falls_through_to_outer() {
+    let outer = Rc::new(RefCell::new(Environment::new()));
+    outer.borrow_mut().set("a", Object::Integer(1));
+    outer.borrow_mut().set("b", Object::Integer(2));
+
+    let mut inner = Environment::new_enclosed(Rc::clone(&outer));
+    inner.set("b", Object::Boolean(true));
+
+    assert_eq!(inner.get("a"), Some(Object::Integer(1)));
+    assert_eq!(inner.get("b"), Some(Object::Boolean(true)));
+    assert_eq!(outer.borrow().get("b"), Some(Object::Integer(2)));
+    assert_eq!(inner.get("c"), None);
+}
+
+#[test]
+fn test_string_expressions() {
+    let tests = vec![
+        (
+            r#""Hello World!""#,
+            Object::String("Hello World!".to_string()),
+        ),
+        (
+            r#""Hello" + " " + "World!""#,
+            Object::String("Hello World!".to_string()),
+        ),
+        (
+            r#"let greet = fn(name) { "hi " + name }; greet("monkey")"#,
+            Object::String("hi monkey".to_string()),
+        ),
+        (r#""a" == "a""#, Object::Boolean(true)),
+        (r#""a" != "a""#, Object::Boolean(false)),
+        (r#""a" == "b""#, Object::Boolean(false)),
+        (
+            r#""Hello" - "World""#,
+            error("unknown operator: STRING - STRING"),
+        ),
+        (r#""one" + 1"#, error("type mismatch: STRING + INTEGER")),
+    ];
+
+    for (input, expected) in tests {
+        assert_eq!(eval(input), expected, "input: {}", input);
+    }
+}
+
+#[test]
+fn test_array_literals_and_indexing() {
+    assert_eq!(
+        eval("[1, 2 * 2, 3 + 3]"),
+        Object::Array(Rc::new(vec![
+            Object::Integer(1),
+            Object::Integer(4),
+            Object::Integer(6)
+        ]))
+    );
+
+    let tests = vec![
+        ("[1, 2, 3][0]", Object::Integer(1)),
+        ("[1, 2, 3][1 + 1]", Object::Integer(3)),
+        ("let i = 0; [1][i];", Object::Integer(1)),
+        ("let a = [1, 2, 3]; a[0] + a[1] + a[2];", Object::Integer(6)),
+        ("[1, 2, 3][3]", Object::Null),
+        ("[1, 2, 3][-1]", Object::Null),
+        ("1[0]", error("index operator not supported: INTEGER")),
+    ];
+
+    for (input, expected) in tests {
+        assert_eq!(eval(input), expected, "input: {}", input);
+    }
+}
+
+#[test]
+fn test_hash_literals_and_indexing() {
+    let tests = vec![
+        (r#"{"foo": 5}["foo"]"#, Object::Integer(5)),
+        (r#"{"foo": 5}["bar"]"#, Object::Null),
+        (r#"let key = "foo"; {"foo": 5}[key]"#, Object::Integer(5)),
+        (r#"{}["foo"]"#, Object::Null),
+        ("{5: 5}[5]", Object::Integer(5)),
+        ("{true: 5}[true]", Object::Integer(5)),
+        ("{false: 5}[false]", Object::Integer(5)),
+        (r#"{"a": 1, "a": 2}["a"]"#, Object::Integer(2)),
+        (
+            r#"{"name": "Monkey"}[fn(x) { x }];"#,
+            error("unusable as hash key: FUNCTION"),
+        ),
+        (r#"{[1]: "array"}"#, error("unusable as hash key: ARRAY")),
+    ];
+
+    for (input, expected) in tests {
+        assert_eq!(eval(input), expected, "input: {}", input);
+    }
+}
+
+#[test]
+fn test_collection_inspect() {
+    let tests = vec![
+        (r#"[1, "two", [true]]"#, r#"[1, "two", [true]]"#),
+        (
+            r#"let two = "two"; {"one": 10 - 9, two: 1 + 1, 4: 4, true: 5}"#,
+            r#"{4: 4, true: 5, "one": 1, "two": 2}"#,
+        ),
+    ];
+
+    for (input, expected) in tests {
+        assert_eq!(eval(input).to_string(), expected, "input: {}", input);
+    }
+}
+
+#[test]
+fn test_reading_a_collection_shares_it() {
+    let (program, _) = parse("let a = [1, 2, 3]; let h = {1: a}; [a, a, h, h]");
+    let env = Rc::new(RefCell::new(Environment::new()));
+    let values = match eval_program(&program, &env) {
+        Object::Array(values) => values,
+        other => panic!("expected an array, got {:?}", other),
+    };
+
+    match (&values[0], &values[1], &values[2], &values[3]) {
+        (Object::Array(a), Object::Array(b), Object::Hash(g), Object::Hash(h)) => {
+            assert!(Rc::ptr_eq(a, b));
+            assert!(Rc::ptr_eq(g, h));
+        }
+        other => panic!("unexpected values {:?}", other),
+    }
+}
+
+#[test]
+fn test_builtin_functions() {
+    let int_array = |values: &[i64]| {
+        Object::Array(Rc::new(
+            values.iter().map(|v| Object::Integer(*v)).collect(),
+        ))
+    };
+
+    let tests = vec![
+        (r#"len("")"#, Object::Integer(0)),
+        (r#"len("four")"#, Object::Integer(4)),
+        (r#"len("héllo")"#, Object::Integer(5)),
+        ("len([1, 2, 3])", Object::Integer(3)),
+        (r#"len({"a": 1})"#, Object::Integer(1)),
+        (
+            "len(1)",
+            error("argument to `len` not supported, got INTEGER"),
+        ),
+        (
+            r#"len("one", "two")"#,
+            error("wrong number of arguments: want=1, got=2"),
+        ),
+        ("first([1, 2, 3])", Object::Integer(1)),
+        ("first([])", Object::Null),
+        (
+            "first(1)",
+            error("argument to `first` must be ARRAY, got INTEGER"),
+        ),
+        ("last([1, 2, 3])", Object::Integer(3)),
+        ("last([])", Object::Null),
+        (
+            r#"last("abc")"#,
+            error("argument to `last` must be ARRAY, got STRING"),
+        ),
+        ("rest([1, 2, 3])", int_array(&[2, 3])),
+        ("rest([1])", int_array(&[])),
+        ("rest([])", Object::Null),
+        ("push([], 1)", int_array(&[1])),
+        ("let a = [1]; let b = push(a, 2); a;", int_array(&[1])),
+        (
+            "push(1, 1)",
+            error("argument to `push` must be ARRAY, got INTEGER"),
+        ),
+        (
+            "push([1])",
+            error("wrong number of arguments: want=2, got=1"),
+        ),
+        (r#"puts("hello", 1)"#, Object::Null),
+    ];
+
+    for (input, expected) in tests {
+        assert_eq!(eval(input), expected, "input: {}", input);
+    }
+}
+
+#[test]
+fn test_builtins_compose_with_user_functions() {
+    let input = "
+        let map = fn(arr, f) {
+            let iter = fn(arr, accumulated) {
+                if (len(arr) == 0) {
+                    accumulated
+                } else {
+                    iter(rest(arr), push(accumulated, f(first(arr))));
+                }
+            };
+            iter(arr, []);
+        };
+        let double = fn(x) { x * 2 };
+        map([1, 2, 3], double);";
+
+    assert_eq!(
+        eval(input),
+        Object::Array(Rc::new(vec![
+            Object::Integer(2),
+            Object::Integer(4),
+            Object::Integer(6)
+        ]))
+    );
+
+    // bindings shadow builtins of the same name
+    assert_eq!(
+        eval("let len = fn(x) { 42 }; len([1])"),
+        Object::Integer(42)
+    );
+    assert_eq!(eval("len").to_string(), "builtin function len");
+}
+
+#[test]
+fn test_runtime_error_spans() {
+    let tests = vec![
+        (
+            "1 + 2;\nlet x = 5 + true;",
+            "2:9: type mismatch: INTEGER + BOOLEAN",
+        ),
+        (
+            "let f = fn(a) {\n  a * nope\n};\nf(1)",
+            "2:7: identifier not found: nope",
+        ),
+        (
+            "let f = fn(a) { a };\nf(1, 2)",
+            "2:1: wrong number of arguments: want=1, got=2",
+        ),
+        (
+            "len(1) + 2",
+            "1:1: argument to `len` not supported, got INTEGER",
+        ),
+        ("[1, 2][-(true)]", "1:8: unknown operator: -BOOLEAN"),
+    ];
+
+    for (input, expected) in tests {
+        match eval_with_spans(input) {
+            Object::Error(err) => assert_eq!(err.to_string(), expected, "input: {}", input),
+            other => panic!("expected an error for {:?}, got {:?}", input, other),
+        }
+    }
+}
+
+#[test]
+fn test_eval_float_expression() {
+    let tests = vec![
+        ("3.5", 3.5),
+        ("-2.5", -2.5),
+        ("0.5 + 0.25", 0.75),
+        ("1.5 * 4.0 - 1e1", -4.0),
+        ("7.0 / 2.0", 3.5),
+        // an integer meeting a float is promoted
+        ("1 + 0.5", 1.5),
+        ("0.5 * 4", 2.0),
+        ("7 / 2.0", 3.5),
+        ("2 * (1.5 + 0x1)", 5.0),
+    ];
+
+    for (input, expected) in tests {
+        assert_eq!(eval(input), Object::Float(expected), "input: {}", input);
+    }
+
+    let comparisons = vec![
+        ("1 == 1.0", true),
+        ("1.0 != 1", false),
+        ("0.1 + 0.2 > 0.3", true),
+        ("2 < 2.5", true),
+        ("-1.5 > -1", false),
+    ];
+    for (input, expected) in comparisons {
+        assert_eq!(eval(input), Object::Boolean(expected), "input: {}", input);
+    }
+
+    assert_eq!(eval("1.0 / 0"), error("division by zero"));
+    assert_eq!(eval("1.5 + true"), error("type mismatch: FLOAT + BOOLEAN"));
+    assert_eq!(eval("{1.5: 1}"), error("unusable as hash key: FLOAT"));
+    assert_eq!(eval("[2.0, 1e20, 0.1]").to_string(), "[2.0, 1e20, 0.1]");
+}
+
+#[test]
+fn test_eval_new_operators() {
+    let tests = vec![
+        ("7 % 3", Object::Integer(1)),
+        ("-7 % 3", Object::Integer(-1)),
+        ("7.5 % 2", Object::Float(1.5)),
+        ("2 ** 10", Object::Integer(1024)),
+        ("2 ** 3 ** 2", Object::Integer(512)),
+        ("-2 ** 2", Object::Integer(-4)),
+        ("2 ** -1", Object::Float(0.5)),
+        ("4.0 ** 0.5", Object::Float(2.0)),
+        ("1 <= 1", Object::Boolean(true)),
+        ("2 <= 1", Object::Boolean(false)),
+        ("1 >= 2", Object::Boolean(false)),
+        ("1.5 >= 1", Object::Boolean(true)),
+        ("true && false", Object::Boolean(false)),
+        ("true && 1", Object::Boolean(true)),
+        ("false || if (false) { 1 }", Object::Boolean(false)),
+        ("0 || false", Object::Boolean(true)),
+        ("1 < 2 && 2 < 3", Object::Boolean(true)),
+    ];
+
+    for (input, expected) in tests {
+        assert_eq!(eval(input), expected, "input: {}", input);
+    }
+
+    assert_eq!(eval("5 % 0"), error("division by zero"));
+    assert_eq!(eval("2 ** 64"), error("integer overflow: 2 ** 64"));
+    assert_eq!(
+        eval("true <= false"),
+        error("unknown operator: BOOLEAN <= BOOLEAN")
+    );
+}
+
+#[test]
+fn test_logical_operators_short_circuit() {
+    // the right side would be an error if it were evaluated
+    assert_eq!(eval("false && missing"), Object::Boolean(false));
+    assert_eq!(eval("true || 1 / 0"), Object::Boolean(true));
+    assert_eq!(
+        eval("true && missing"),
+        error("identifier not found: missing")
+    );
+    assert_eq!(eval("false || 1 / 0"), error("division by zero"));
+}
+
+#[test]
+fn test_calls_free_their_scope() {
+    let (program, errors) = parse(
+        "let f = fn(n) { let double = fn(x) { x * 2 }; double(n) };
+        f(1); f(2); f(3);",
+    );
+    assert!(errors.is_empty(), "parser had errors: {:#?}", errors);
+
+    let env = Rc::new(RefCell::new(Environment::new()));
+    assert_eq!(eval_program(&program, &env), Object::Integer(6));
+    // Held by this test and by f. Each call's scope would add another
+    // if the function bound in it kept it alive.
+    assert_eq!(Rc::strong_count(&env), 2);
+}
+
+#[test]
+fn test_escaping_closures_keep_their_scope() {
+    let tests = vec![
+        (
+            "let make = fn(x) { let get = fn() { x }; [get] }; make(5)[0]()",
+            5,
+        ),
+        (
+            "let make = fn(x) { {\"get\": fn() { x }} }; make(6)[\"get\"]()",
+            6,
+        ),
+        (
+            "let wrap = fn(g) { fn() { g() } };
+            let f = fn(x) { let k = fn() { x }; wrap(k) };
+            f(7)()",
+            7,
+        ),
+    ];
+
+    for (input, expected) in tests {
+        assert_eq!(eval(input), Object::Integer(expected), "input: {}", input);
+    }
+}
+
+#[test]
+fn test_recursion_depth_limit() {
+    let input = format!(
+        "let f = fn(n) {{ if (n == 0) {{ 0 }} else {{ 1 + f(n - 1) }} }}; [f({}), f(100000)]",
+        MAX_CALL_DEPTH - 1
+    );
+
+    // Enough native stack to get to the limit, as the binary has
+    let result = std::thread::Builder::new()
+        .stack_size(256 << 20)
+        .spawn(move || {
+            set_stack_budget(128 << 20);
+            eval(&input).to_string()
+        })
+        .unwrap()
+        .join()
+        .unwrap();
+    assert_eq!(result, "ERROR: stack overflow");
+
+    // Without a bigger stack, the native stack runs out long before the
+    // depth limit, and that's an error too rather than a crash
+    assert_eq!(
+        eval("let f = fn(n) { if (n == 0) { 0 } else { 1 + f(n - 1) } }; f(100000)").to_string(),
+        "ERROR: stack overflow"
+    );
+    assert_eq!(
+        eval("let f = fn(n) { if (n == 0) { 0 } else { 1 + f(n - 1) } }; f(50)"),
+        Object::Integer(50)
+    );
+
+    // Depth is only counted while calls are running
+    assert_eq!(
+        eval("let f = fn(n) { if (n == 0) { 0 } else { 1 + f(n - 1) } }; f(10) + f(10)"),
+        Object::Integer(20)
+    );
+}
@@ -0,0 +1,92 @@
+use monkey::{
+    formatter::{first_comment, format_program},
+    parser::parse,
+    token::Span,
+};
+
+fn format(input: &str) -> String {
+    let (program, errors) = parse(input);
+    assert!(errors.is_empty(), "parser had errors: {:#?}", errors);
+    format_program(&program)
+}
+
+#[test]
+fn test_format_program() {
+    let input = "let max=fn(a,b){if(a>b){return a;}else{b}};let xs=[1,2*(3+4)];max(xs[0],{\"k\":-1}[\"k\"])";
+    let expected = r#"let max = fn(a, b) {
+    if (a > b) {
+        return a;
+    } else {
+        b
+    }
+};
+let xs = [1, 2 * (3 + 4)];
+max(xs[0], {"k": -1}["k"]);
+"#;
+    assert_eq!(format(input), expected);
+}
+
+#[test]
+fn test_format_only_needed_parentheses() {
+    let tests = vec![
+        ("(x + 1)", "x + 1;\n"),
+        ("(a + b) + c", "a + b + c;\n"),
+        ("a + (b + c)", "a + (b + c);\n"),
+        ("a * (b + c)", "a * (b + c);\n"),
+        ("(a * b) + c", "a * b + c;\n"),
+        ("-(a + b)", "-(a + b);\n"),
+        ("(-a)[0]", "(-a)[0];\n"),
+        ("-(a ** 2)", "-a ** 2;\n"),
+        ("(-a) ** 2", "(-a) ** 2;\n"),
+        ("2 ** (3 ** 2)", "2 ** 3 ** 2;\n"),
+        ("(2 ** 3) ** 2", "(2 ** 3) ** 2;\n"),
+        ("2 ** -(1)", "2 ** -1;\n"),
+        ("(a || b) && c", "(a || b) && c;\n"),
+        ("(fn(x) { x })(1)", "fn(x) {\n    x\n}(1);\n"),
+        ("(f(1))[2]", "f(1)[2];\n"),
+        ("!(!(a))", "!!a;\n"),
+    ];
+
+    for (input, expected) in tests {
+        assert_eq!(format(input), expected, "input: {}", input);
+    }
+}
+
+#[test]
+fn test_format_round_trips() {
+    let inputs = vec![
+        "let a = 1 - (2 - 3) * -4 ** 2 % 5;",
+        "if (a <= b || !(c >= d) && e != f) { g(h)[i] } else { }",
+        "let f = fn(n) { if (n < 2) { return n; }; f(n - 1) + f(n - 2) };",
+        "[{\"a\\t\": [1.5, 2e-9]}[\"a\\t\"][0], fn() { fn(y) { y } }(1)(2)]",
+        "(-2) ** (3 ** -(1 + 1)) ** 2",
+    ];
+
+    for input in inputs {
+        let formatted = format(input);
+        let (original, _) = parse(input);
+        let (reparsed, errors) = parse(&formatted);
+        assert!(
+            errors.is_empty(),
+            "{} didn't parse: {:#?}",
+            formatted,
+            errors
+        );
+        assert_eq!(reparsed, original, "formatted: {}", formatted);
+        assert_eq!(format(&formatted), formatted);
+    }
+}
+
+#[test]
+fn test_first_comment() {
+    assert_eq!(first_comment("let a = 1;\nlet b = 2;"), None);
+    assert_eq!(
+        first_comment("let a = 1;\n/* b */ let b = 2; # c"),
+        Some(Span {
+            start: 11,
+            end: 18,
+            line: 2,
+            column: 1
+        })
+    );
+}
@@ -1,58 +1,47 @@
-use monkey::{lexer::Lexer, token, token::TokenType};
+use monkey::{
+    lexer::{diagnose, tokenize, InvalidSource, LexError, LexErrorKind, Lexer},
+    token::{Span, Token, TokenRef, TokenType},
+};
+
+// Lexes as many tokens as were expected and compares their kinds and literals
+fn assert_tokens(lex: &mut Lexer, expected_tokens: Vec<(TokenType, &str)>) {
+    let expected_tokens: Vec<(TokenType, String)> = expected_tokens
+        .into_iter()
+        .map(|(kind, literal)| (kind, literal.to_string()))
+        .collect();
+
+    let actual_tokens: Vec<(TokenType, String)> = lex
+        .by_ref()
+        .take(expected_tokens.len())
+        .map(|tok: Token| (tok.kind, tok.literal))
+        .collect();
+
+    assert_eq!(expected_tokens, actual_tokens);
+}
 
 #[test]
 fn test_next_token() {
     let mut lex = Lexer::new(String::from("=+(){},;"));
 
     let expected_tokens = vec![
-        token::Token {
-            kind: TokenType::ASSIGN,
-            literal: "=".to_string(),
-        },
-        token::Token {
-            kind: TokenType::PLUS,
-            literal: "+".to_string(),
-        },
-        token::Token {
-            kind: TokenType::LPAREN,
-            literal: "(".to_string(),
-        },
-        token::Token {
-            kind: TokenType::RPAREN,
-            literal: ")".to_string(),
-        },
-        token::Token {
-            kind: TokenType::LBRACE,
-            literal: "{".to_string(),
-        },
-        token::Token {
-            kind: TokenType::RBRACE,
-            literal: "}".to_string(),
-        },
-        token::Token {
-            kind: TokenType::COMMA,
-            literal: ",".to_string(),
-        },
-        token::Token {
-            kind: TokenType::SEMICOLON,
-            literal: ";".to_string(),
-        },
+        (TokenType::ASSIGN, "="),
+        (TokenType::PLUS, "+"),
+        (TokenType::LPAREN, "("),
+        (TokenType::RPAREN, ")"),
+        (TokenType::LBRACE, "{"),
+        (TokenType::RBRACE, "}"),
+        (TokenType::COMMA, ","),
+        (TokenType::SEMICOLON, ";"),
         // token::Token {
         //     kind: token::EOF.to_string(),
         //     literal: "".to_string(),
         // },
     ];
 
-    let mut actual_tokens: Vec<token::Token> = Vec::new();
-
-    for _ in expected_tokens.iter() {
-        let tok: token::Token = lex.next_token();
-        actual_tokens.push(tok);
-    }
-
-    assert_eq!(expected_tokens, actual_tokens);
+    assert_tokens(&mut lex, expected_tokens);
 }
 
+#[allow(unused_mut)]
 #[test]
 fn test_whitespace_consumption() {
     let mut lex = Lexer::new(String::from("hello  \n\r\t  world"));
@@ -82,56 +71,19 @@ fn test_read_minimal_source() {
     let mut lex = Lexer::new(String::from("let five = 5;\nlet ten = 10;"));
 
     let expected_tokens = vec![
-        token::Token {
-            kind: TokenType::LET,
-            literal: "let".to_string(),
-        },
-        token::Token {
-            kind: TokenType::IDENT,
-            literal: "five".to_string(),
-        },
-        token::Token {
-            kind: TokenType::ASSIGN,
-            literal: "=".to_string(),
-        },
-        token::Token {
-            kind: TokenType::INT,
-            literal: "5".to_string(),
-        },
-        token::Token {
-            kind: TokenType::SEMICOLON,
-            literal: ";".to_string(),
-        },
-        token::Token {
-            kind: TokenType::LET,
-            literal: "let".to_string(),
-        },
-        token::Token {
-            kind: TokenType::IDENT,
-            literal: "ten".to_string(),
-        },
-        token::Token {
-            kind: TokenType::ASSIGN,
-            literal: "=".to_string(),
-        },
-        token::Token {
-            kind: TokenType::INT,
-            literal: "10".to_string(),
-        },
-        token::Token {
-            kind: TokenType::SEMICOLON,
-            literal: ";".to_string(),
-        },
+        (TokenType::LET, "let"),
+        (TokenType::IDENT, "five"),
+        (TokenType::ASSIGN, "="),
+        (TokenType::INT, "5"),
+        (TokenType::SEMICOLON, ";"),
+        (TokenType::LET, "let"),
+        (TokenType::IDENT, "ten"),
+        (TokenType::ASSIGN, "="),
+        (TokenType::INT, "10"),
+        (TokenType::SEMICOLON, ";"),
     ];
 
-    let mut actual_tokens: Vec<token::Token> = Vec::new();
-
-    for _ in expected_tokens.iter() {
-        let tok: token::Token = lex.next_token();
-        actual_tokens.push(tok);
-    }
-
-    assert_eq!(expected_tokens, actual_tokens);
+    assert_tokens(&mut lex, expected_tokens);
 }
 
 #[test]
@@ -142,7 +94,7 @@ fn test_read_expanded_source() {
             x + y;
         };
         let result = add(five, ten);
-        !-/*5;
+        !-/ *5; // `/*` would open a block comment
         5 < 10 > 5;
 
         if (5 < 10) {
@@ -154,274 +106,678 @@ fn test_read_expanded_source() {
     ));
 
     let expected_tokens = vec![
-        token::Token {
-            kind: TokenType::LET,
-            literal: "let".to_string(),
-        },
-        token::Token {
-            kind: TokenType::IDENT,
-            literal: "five".to_string(),
-        },
-        token::Token {
-            kind: TokenType::ASSIGN,
-            literal: "=".to_string(),
-        },
-        token::Token {
-            kind: TokenType::INT,
-            literal: "5".to_string(),
-        },
-        token::Token {
-            kind: TokenType::SEMICOLON,
-            literal: ";".to_string(),
-        },
-        token::Token {
-            kind: TokenType::LET,
-            literal: "let".to_string(),
-        },
-        token::Token {
-            kind: TokenType::IDENT,
-            literal: "ten".to_string(),
-        },
-        token::Token {
-            kind: TokenType::ASSIGN,
-            literal: "=".to_string(),
-        },
-        token::Token {
-            kind: TokenType::INT,
-            literal: "10".to_string(),
-        },
-        token::Token {
-            kind: TokenType::SEMICOLON,
-            literal: ";".to_string(),
-        },
-        token::Token {
-            kind: TokenType::LET,
-            literal: "let".to_string(),
-        },
-        token::Token {
-            kind: TokenType::IDENT,
-            literal: "add".to_string(),
-        },
-        token::Token {
-            kind: TokenType::ASSIGN,
-            literal: "=".to_string(),
-        },
-        token::Token {
-            kind: TokenType::FUNCTION,
-            literal: "fn".to_string(),
-        },
-        token::Token {
-            kind: TokenType::LPAREN,
-            literal: "(".to_string(),
-        },
-        token::Token {
-            kind: TokenType::IDENT,
-            literal: "x".to_string(),
-        },
-        token::Token {
-            kind: TokenType::COMMA,
-            literal: ",".to_string(),
-        },
-        token::Token {
-            kind: TokenType::IDENT,
-            literal: "y".to_string(),
-        },
-        token::Token {
-            kind: TokenType::RPAREN,
-            literal: ")".to_string(),
-        },
-        token::Token {
-            kind: TokenType::LBRACE,
-            literal: "{".to_string(),
-        },
-        token::Token {
-            kind: TokenType::IDENT,
-            literal: "x".to_string(),
-        },
-        token::Token {
-            kind: TokenType::PLUS,
-            literal: "+".to_string(),
-        },
-        token::Token {
-            kind: TokenType::IDENT,
-            literal: "y".to_string(),
-        },
-        token::Token {
-            kind: TokenType::SEMICOLON,
-            literal: ";".to_string(),
-        },
-        token::Token {
-            kind: TokenType::RBRACE,
-            literal: "}".to_string(),
-        },
-        token::Token {
-            kind: TokenType::SEMICOLON,
-            literal: ";".to_string(),
-        },
-        token::Token {
-            kind: TokenType::LET,
-            literal: "let".to_string(),
-        },
-        token::Token {
-            kind: TokenType::IDENT,
-            literal: "result".to_string(),
-        },
-        token::Token {
-            kind: TokenType::ASSIGN,
-            literal: "=".to_string(),
-        },
-        token::Token {
-            kind: TokenType::IDENT,
-            literal: "add".to_string(),
-        },
-        token::Token {
-            kind: TokenType::LPAREN,
-            literal: "(".to_string(),
-        },
-        token::Token {
-            kind: TokenType::IDENT,
-            literal: "five".to_string(),
-        },
-        token::Token {
-            kind: TokenType::COMMA,
-            literal: ",".to_string(),
-        },
-        token::Token {
-            kind: TokenType::IDENT,
-            literal: "ten".to_string(),
-        },
-        token::Token {
-            kind: TokenType::RPAREN,
-            literal: ")".to_string(),
-        },
-        token::Token {
-            kind: TokenType::SEMICOLON,
-            literal: ";".to_string(),
-        },
-        token::Token {
-            kind: TokenType::BANG,
-            literal: "!".to_string(),
-        },
-        token::Token {
-            kind: TokenType::MINUS,
-            literal: "-".to_string(),
-        },
-        token::Token {
-            kind: TokenType::SLASH,
-            literal: "/".to_string(),
-        },
-        token::Token {
-            kind: TokenType::ASTERISK,
-            literal: "*".to_string(),
-        },
-        token::Token {
-            kind: TokenType::INT,
-            literal: "5".to_string(),
-        },
-        token::Token {
-            kind: TokenType::SEMICOLON,
-            literal: ";".to_string(),
-        },
-        token::Token {
-            kind: TokenType::INT,
-            literal: "5".to_string(),
-        },
-        token::Token {
-            kind: TokenType::LT,
-            literal: "<".to_string(),
-        },
-        token::Token {
-            kind: TokenType::INT,
-            literal: "10".to_string(),
-        },
-        token::Token {
-            kind: TokenType::GT,
-            literal: ">".to_string(),
-        },
-        token::Token {
-            kind: TokenType::INT,
-            literal: "5".to_string(),
-        },
-        token::Token {
-            kind: TokenType::SEMICOLON,
-            literal: ";".to_string(),
-        },
-        token::Token {
-            kind: TokenType::IF,
-            literal: "if".to_string(),
-        },
-        token::Token {
-            kind: TokenType::LPAREN,
-            literal: "(".to_string(),
-        },
-        token::Token {
-            kind: TokenType::INT,
-            literal: "5".to_string(),
-        },
-        token::Token {
-            kind: TokenType::LT,
-            literal: "<".to_string(),
-        },
-        token::Token {
-            kind: TokenType::INT,
-            literal: "10".to_string(),
-        },
-        token::Token {
-            kind: TokenType::RPAREN,
-            literal: ")".to_string(),
-        },
-        token::Token {
-            kind: TokenType::LBRACE,
-            literal: "{".to_string(),
-        },
-        token::Token {
-            kind: TokenType::RETURN,
-            literal: "return".to_string(),
-        },
-        token::Token {
-            kind: TokenType::TRUE,
-            literal: "true".to_string(),
-        },
-        token::Token {
-            kind: TokenType::SEMICOLON,
-            literal: ";".to_string(),
-        },
-        token::Token {
-            kind: TokenType::RBRACE,
-            literal: "}".to_string(),
-        },
-        token::Token {
-            kind: TokenType::ELSE,
-            literal: "else".to_string(),
-        },
-        token::Token {
-            kind: TokenType::LBRACE,
-            literal: "{".to_string(),
-        },
-        token::Token {
-            kind: TokenType::RETURN,
-            literal: "return".to_string(),
-        },
-        token::Token {
-            kind: TokenType::FALSE,
-            literal: "false".to_string(),
-        },
-        token::Token {
-            kind: TokenType::SEMICOLON,
-            literal: ";".to_string(),
-        },
-        token::Token {
-            kind: TokenType::RBRACE,
-            literal: "}".to_string(),
-        },
+        (TokenType::LET, "let"),
+        (TokenType::IDENT, "five"),
+        (TokenType::ASSIGN, "="),
+        (TokenType::INT, "5"),
+        (TokenType::SEMICOLON, ";"),
+        (TokenType::LET, "let"),
+        (TokenType::IDENT, "ten"),
+        (TokenType::ASSIGN, "="),
+        (TokenType::INT, "10"),
+        (TokenType::SEMICOLON, ";"),
+        (TokenType::LET, "let"),
+        (TokenType::IDENT, "add"),
+        (TokenType::ASSIGN, "="),
+        (TokenType::FUNCTION, "fn"),
+        (TokenType::LPAREN, "("),
+        (TokenType::IDENT, "x"),
+        (TokenType::COMMA, ","),
+        (TokenType::IDENT, "y"),
+        (TokenType::RPAREN, ")"),
+        (TokenType::LBRACE, "{"),
+        (TokenType::IDENT, "x"),
+        (TokenType::PLUS, "+"),
+        (TokenType::IDENT, "y"),
+        (TokenType::SEMICOLON, ";"),
+        (TokenType::RBRACE, "}"),
+        (TokenType::SEMICOLON, ";"),
+        (TokenType::LET, "let"),
+        (TokenType::IDENT, "result"),
+        (TokenType::ASSIGN, "="),
+        (TokenType::IDENT, "add"),
+        (TokenType::LPAREN, "("),
+        (TokenType::IDENT, "five"),
+        (TokenType::COMMA, ","),
+        (TokenType::IDENT, "ten"),
+        (TokenType::RPAREN, ")"),
+        (TokenType::SEMICOLON, ";"),
+        (TokenType::BANG, "!"),
+        (TokenType::MINUS, "-"),
+        (TokenType::SLASH, "/"),
+        (TokenType::ASTERISK, "*"),
+        (TokenType::INT, "5"),
+        (TokenType::SEMICOLON, ";"),
+        (TokenType::INT, "5"),
+        (TokenType::LT, "<"),
+        (TokenType::INT, "10"),
+        (TokenType::GT, ">"),
+        (TokenType::INT, "5"),
+        (TokenType::SEMICOLON, ";"),
+        (TokenType::IF, "if"),
+        (TokenType::LPAREN, "("),
+        (TokenType::INT, "5"),
+        (TokenType::LT, "<"),
+        (TokenType::INT, "10"),
+        (TokenType::RPAREN, ")"),
+        (TokenType::LBRACE, "{"),
+        (TokenType::RETURN, "return"),
+        (TokenType::TRUE, "true"),
+        (TokenType::SEMICOLON, ";"),
+        (TokenType::RBRACE, "}"),
+        (TokenType::ELSE, "else"),
+        (TokenType::LBRACE, "{"),
+        (TokenType::RETURN, "return"),
+        (TokenType::FALSE, "false"),
+        (TokenType::SEMICOLON, ";"),
+        (TokenType::RBRACE, "}"),
     ];
 
-    let mut actual_tokens: Vec<token::Token> = Vec::new();
+    assert_tokens(&mut lex, expected_tokens);
+}
 
-    for _ in expected_tokens.iter() {
-        let tok: token::Token = lex.next_token();
-        actual_tokens.push(tok);
+#[test]
+fn test_read_string() {
+    let mut lex = Lexer::new(String::from(
+        r#""foobar" "foo bar" "" "tab\there" "line\nbreak" "say \"hi\"" "back\\slash" "\u{1F600}\u{e9}""#,
+    ));
+
+    let expected_tokens = vec![
+        (TokenType::STRING, "foobar"),
+        (TokenType::STRING, "foo bar"),
+        (TokenType::STRING, ""),
+        (TokenType::STRING, "tab\there"),
+        (TokenType::STRING, "line\nbreak"),
+        (TokenType::STRING, "say \"hi\""),
+        (TokenType::STRING, "back\\slash"),
+        (TokenType::STRING, "\u{1F600}\u{e9}"),
+        (TokenType::EOF, ""),
+    ];
+
+    assert_tokens(&mut lex, expected_tokens);
+}
+
+#[test]
+fn test_read_invalid_string() {
+    let mut lex = Lexer::new(String::from(
+        r#""bad \q escape" "bad \u{zz}" "fine" "never closed"#,
+    ));
+
+    let expected_tokens = vec![
+        (TokenType::ILLEGAL, r#""bad \q escape""#),
+        (TokenType::ILLEGAL, r#""bad \u{zz}""#),
+        (TokenType::STRING, "fine"),
+        (TokenType::ILLEGAL, r#""never closed"#),
+        (TokenType::EOF, ""),
+    ];
+
+    assert_tokens(&mut lex, expected_tokens);
+}
+
+#[test]
+fn test_read_brackets_and_colon() {
+    let mut lex = Lexer::new(String::from(r#"[1, 2]; {"foo": "bar"}"#));
+
+    let expected_tokens = vec![
+        (TokenType::LBRACKET, "["),
+        (TokenType::INT, "1"),
+        (TokenType::COMMA, ","),
+        (TokenType::INT, "2"),
+        (TokenType::RBRACKET, "]"),
+        (TokenType::SEMICOLON, ";"),
+        (TokenType::LBRACE, "{"),
+        (TokenType::STRING, "foo"),
+        (TokenType::COLON, ":"),
+        (TokenType::STRING, "bar"),
+        (TokenType::RBRACE, "}"),
+    ];
+
+    assert_tokens(&mut lex, expected_tokens);
+}
+
+#[test]
+fn test_token_spans() {
+    let input = "let x = \"hi\";\n  x == 10";
+    let mut lex = Lexer::new(String::from(input));
+
+    let span = |start, end, line, column| Span {
+        start,
+        end,
+        line,
+        column,
+    };
+    let expected_spans = vec![
+        (TokenType::LET, span(0, 3, 1, 1)),
+        (TokenType::IDENT, span(4, 5, 1, 5)),
+        (TokenType::ASSIGN, span(6, 7, 1, 7)),
+        (TokenType::STRING, span(8, 12, 1, 9)),
+        (TokenType::SEMICOLON, span(12, 13, 1, 13)),
+        (TokenType::IDENT, span(16, 17, 2, 3)),
+        (TokenType::EQ, span(18, 20, 2, 5)),
+        (TokenType::INT, span(21, 23, 2, 8)),
+        (TokenType::EOF, span(23, 23, 2, 10)),
+    ];
+
+    for (kind, span) in expected_spans {
+        let tok = lex.next_token();
+        assert_eq!((tok.kind, tok.span), (kind, span));
     }
+}
 
-    assert_eq!(expected_tokens, actual_tokens);
+#[test]
+fn test_multibyte_source() {
+    let input = "let café = \"naïve ☕\";\nλ != 10";
+    let mut lex = Lexer::new(String::from(input));
+
+    let expected = vec![
+        (TokenType::LET, "let", 1, 1),
+        (TokenType::IDENT, "café", 1, 5),
+        (TokenType::ASSIGN, "=", 1, 10),
+        (TokenType::STRING, "naïve ☕", 1, 12),
+        (TokenType::SEMICOLON, ";", 1, 21),
+        (TokenType::IDENT, "λ", 2, 1),
+        (TokenType::NEQ, "!=", 2, 3),
+        (TokenType::INT, "10", 2, 6),
+        (TokenType::EOF, "", 2, 8),
+    ];
+
+    for (kind, literal, line, column) in expected {
+        let tok = lex.next_token();
+        assert_eq!(
+            (
+                &tok.kind,
+                tok.literal.as_str(),
+                tok.span.line,
+                tok.span.column
+            ),
+            (&kind, literal, line, column)
+        );
+
+        // spans are byte offsets, so they always slice the source cleanly
+        let text = &input[tok.span.start..tok.span.end];
+        if kind == TokenType::STRING {
+            assert_eq!(text, "\"naïve ☕\"");
+        } else {
+            assert_eq!(text, literal);
+        }
+    }
+}
+
+#[test]
+fn test_nul_in_source_is_not_eof() {
+    let mut lex = Lexer::new(String::from("a\0b"));
+
+    let expected_tokens = vec![
+        (TokenType::IDENT, "a"),
+        (TokenType::ILLEGAL, "\0"),
+        (TokenType::IDENT, "b"),
+        (TokenType::EOF, ""),
+    ];
+
+    assert_tokens(&mut lex, expected_tokens);
+}
+
+#[test]
+fn test_large_source() {
+    // a quadratic lexer takes minutes on this, a linear one a blink
+    let snippet = "let añadir = fn(x, y) { x + y; }; añadir(1, 2);\n";
+    let input = snippet.repeat(1024 * 1024 / snippet.len());
+    let mut lex = Lexer::new(input);
+
+    let mut count = 0;
+    while lex.next_token().kind != TokenType::EOF {
+        count += 1;
+    }
+
+    assert_eq!(count, 23 * (1024 * 1024 / snippet.len()));
+}
+
+#[test]
+fn test_borrowed_tokens() {
+    let input = String::from(r#"let s = "a\tb"; "bad \q" café"#);
+    let mut lex = Lexer::new(input.as_str());
+
+    let mut tokens: Vec<TokenRef> = Vec::new();
+    loop {
+        let tok = lex.next_token_ref();
+        let done = tok.kind == TokenType::EOF;
+        tokens.push(tok);
+        if done {
+            break;
+        }
+    }
+
+    let literals: Vec<(&TokenType, &str)> = tokens.iter().map(|t| (&t.kind, t.literal)).collect();
+    assert_eq!(
+        literals,
+        vec![
+            (&TokenType::LET, "let"),
+            (&TokenType::IDENT, "s"),
+            (&TokenType::ASSIGN, "="),
+            (&TokenType::STRING, r"a\tb"),
+            (&TokenType::SEMICOLON, ";"),
+            (&TokenType::ILLEGAL, r#""bad \q""#),
+            (&TokenType::IDENT, "café"),
+            (&TokenType::EOF, ""),
+        ]
+    );
+
+    // literals are slices of the source, not copies
+    let range = input.as_ptr() as usize..input.as_ptr() as usize + input.len() + 1;
+    for tok in &tokens {
+        assert!(range.contains(&(tok.literal.as_ptr() as usize)));
+    }
+
+    // the owned conversion decodes escapes and matches the owned lexer
+    let mut owned_lex = Lexer::new(input.clone());
+    for tok in tokens {
+        let owned: Token = tok.into();
+        assert_eq!(owned, owned_lex.next_token());
+    }
+}
+
+#[test]
+fn test_lexer_iterator() {
+    let mut lex = Lexer::new("let x = 5;");
+
+    let kinds: Vec<TokenType> = lex.by_ref().map(|tok| tok.kind).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            TokenType::LET,
+            TokenType::IDENT,
+            TokenType::ASSIGN,
+            TokenType::INT,
+            TokenType::SEMICOLON,
+            TokenType::EOF,
+        ]
+    );
+
+    // the iterator stops after EOF, but next_token keeps handing it out
+    assert_eq!(lex.next(), None);
+    assert_eq!(lex.next_token().kind, TokenType::EOF);
+    assert_eq!(lex.next(), None);
+
+    let idents = Lexer::new("a + b * c")
+        .filter(|tok| tok.kind == TokenType::IDENT)
+        .map(|tok| tok.literal)
+        .collect::<Vec<_>>();
+    assert_eq!(idents, vec!["a", "b", "c"]);
+}
+
+#[test]
+fn test_tokenize() {
+    let tokens = tokenize("fn(x) { x }");
+    assert_eq!(tokens.len(), 8);
+    assert_eq!(tokens.last().map(|tok| &tok.kind), Some(&TokenType::EOF));
+    assert_eq!(
+        tokens,
+        Lexer::new(String::from("fn(x) { x }")).collect::<Vec<Token>>()
+    );
+}
+
+#[test]
+fn test_empty_source() {
+    let mut lex = Lexer::new("");
+    assert_eq!(lex.next_token().kind, TokenType::EOF);
+    assert_eq!(lex.next_token().kind, TokenType::EOF);
+
+    let tokens = tokenize("");
+    assert_eq!(tokens.len(), 1);
+    assert_eq!(tokens[0].kind, TokenType::EOF);
+    assert_eq!(
+        tokens[0].span,
+        Span {
+            start: 0,
+            end: 0,
+            line: 1,
+            column: 1
+        }
+    );
+
+    assert_eq!(tokenize("  \n\t ").len(), 1);
+}
+
+#[test]
+fn test_lexer_reset() {
+    let mut lex = Lexer::new(String::from("let x = 1;\nx"));
+    assert_eq!(lex.by_ref().count(), 7);
+
+    lex.reset(String::from("y"));
+    let tokens: Vec<Token> = lex.by_ref().collect();
+    assert_eq!(tokens.len(), 2);
+    assert_eq!(
+        (&tokens[0].kind, tokens[0].literal.as_str()),
+        (&TokenType::IDENT, "y")
+    );
+    assert_eq!(
+        tokens[0].span,
+        Span {
+            start: 0,
+            end: 1,
+            line: 1,
+            column: 1
+        }
+    );
+
+    lex.reset(String::new());
+    assert_eq!(lex.next_token().kind, TokenType::EOF);
+}
+
+#[test]
+fn test_invalid_utf8_source() {
+    let bytes = b"let a = 1;\nlet \xffb = 2;";
+
+    let err = Lexer::from_bytes(bytes).err().unwrap();
+    assert_eq!(
+        err,
+        InvalidSource {
+            span: Span {
+                start: 15,
+                end: 16,
+                line: 2,
+                column: 5
+            }
+        }
+    );
+    assert_eq!(err.to_string(), "2:5: source is not valid UTF-8");
+    assert_eq!(Lexer::from_utf8(bytes.to_vec()).err(), Some(err));
+
+    let mut lex = Lexer::from_bytes("let ☕".as_bytes()).unwrap();
+    assert_eq!(lex.next_token().kind, TokenType::LET);
+    let mut lex = Lexer::from_utf8(b"x".to_vec()).unwrap();
+    assert_eq!(lex.next_token().literal, "x");
+}
+
+#[test]
+fn test_comments_are_skipped() {
+    let mut lex = Lexer::new(String::from(
+        "// leading\nlet x = 10 / 2; # trailing\n/* a /* nested */ block */ x // end",
+    ));
+
+    let expected_tokens = vec![
+        (TokenType::LET, "let"),
+        (TokenType::IDENT, "x"),
+        (TokenType::ASSIGN, "="),
+        (TokenType::INT, "10"),
+        (TokenType::SLASH, "/"),
+        (TokenType::INT, "2"),
+        (TokenType::SEMICOLON, ";"),
+        (TokenType::IDENT, "x"),
+        (TokenType::EOF, ""),
+    ];
+
+    assert_tokens(&mut lex, expected_tokens);
+}
+
+#[test]
+fn test_comments_as_trivia() {
+    let input = "# hash\nx /* one /* two */ */ /**/ // slashes";
+    let tokens: Vec<(TokenType, String, usize, usize)> = Lexer::new(input)
+        .keep_comments(true)
+        .map(|tok| (tok.kind, tok.literal, tok.span.line, tok.span.column))
+        .collect();
+
+    let expected = vec![
+        (TokenType::COMMENT, "# hash", 1, 1),
+        (TokenType::IDENT, "x", 2, 1),
+        (TokenType::COMMENT, "/* one /* two */ */", 2, 3),
+        (TokenType::COMMENT, "/**/", 2, 23),
+        (TokenType::COMMENT, "// slashes", 2, 28),
+        (TokenType::EOF, "", 2, 38),
+    ];
+    let expected: Vec<(TokenType, String, usize, usize)> = expected
+        .into_iter()
+        .map(|(kind, literal, line, column)| (kind, literal.to_string(), line, column))
+        .collect();
+    assert_eq!(tokens, expected);
+
+    // resetting keeps the setting
+    let mut lex = Lexer::new("1").keep_comments(true);
+    lex.reset("#");
+    assert_eq!(lex.next_token().kind, TokenType::COMMENT);
+}
+
+#[test]
+fn test_unterminated_block_comment() {
+    let mut lex = Lexer::new(String::from("let x = 1; /* open /* nested */ still open"));
+
+    let expected_tokens = vec![
+        (TokenType::LET, "let"),
+        (TokenType::IDENT, "x"),
+        (TokenType::ASSIGN, "="),
+        (TokenType::INT, "1"),
+        (TokenType::SEMICOLON, ";"),
+        (TokenType::ILLEGAL, "/* open /* nested */ still open"),
+        (TokenType::EOF, ""),
+    ];
+
+    assert_tokens(&mut lex, expected_tokens);
+    assert_eq!(
+        tokenize("/*/").first().map(|tok| &tok.kind),
+        Some(&TokenType::ILLEGAL)
+    );
+}
+
+#[test]
+fn test_identifiers_with_digits_and_underscores() {
+    let mut lex = Lexer::new(String::from("let x1 = _tmp2 + __ + a_1b; 9lives"));
+
+    let expected_tokens = vec![
+        (TokenType::LET, "let"),
+        (TokenType::IDENT, "x1"),
+        (TokenType::ASSIGN, "="),
+        (TokenType::IDENT, "_tmp2"),
+        (TokenType::PLUS, "+"),
+        (TokenType::IDENT, "__"),
+        (TokenType::PLUS, "+"),
+        (TokenType::IDENT, "a_1b"),
+        (TokenType::SEMICOLON, ";"),
+        (TokenType::ILLEGAL, "9lives"),
+        (TokenType::EOF, ""),
+    ];
+
+    assert_tokens(&mut lex, expected_tokens);
+}
+
+#[test]
+fn test_identifiers_in_mixed_scripts() {
+    let input = "größe2 Δx имя_1 変数 x٣ cafe\u{301} 日本語ok αβγ+ω";
+    let tokens: Vec<(TokenType, String)> = Lexer::new(input)
+        .map(|tok| (tok.kind, tok.literal))
+        .collect();
+
+    let expected = vec![
+        (TokenType::IDENT, "größe2"),
+        (TokenType::IDENT, "Δx"),
+        (TokenType::IDENT, "имя_1"),
+        (TokenType::IDENT, "変数"),
+        (TokenType::IDENT, "x٣"),
+        (TokenType::IDENT, "cafe\u{301}"),
+        (TokenType::IDENT, "日本語ok"),
+        (TokenType::IDENT, "αβγ"),
+        (TokenType::PLUS, "+"),
+        (TokenType::IDENT, "ω"),
+        (TokenType::EOF, ""),
+    ];
+    let expected: Vec<(TokenType, String)> = expected
+        .into_iter()
+        .map(|(kind, literal)| (kind, literal.to_string()))
+        .collect();
+    assert_eq!(tokens, expected);
+
+    // symbols and a lone combining mark can't start an identifier
+    let kinds: Vec<TokenType> = Lexer::new("☕ \u{301}").map(|tok| tok.kind).collect();
+    assert_eq!(
+        kinds,
+        vec![TokenType::ILLEGAL, TokenType::ILLEGAL, TokenType::EOF]
+    );
+}
+
+#[test]
+fn test_read_numbers() {
+    let mut lex = Lexer::new(String::from(
+        "3.14 1e-9 2.5E+3 7e2 0xFF 0o17 0b1010 1_000_000 0x_ff 1.5e1_0 007 [1.2][0]",
+    ));
+
+    let expected_tokens = vec![
+        (TokenType::FLOAT, "3.14"),
+        (TokenType::FLOAT, "1e-9"),
+        (TokenType::FLOAT, "2.5E+3"),
+        (TokenType::FLOAT, "7e2"),
+        (TokenType::INT, "0xFF"),
+        (TokenType::INT, "0o17"),
+        (TokenType::INT, "0b1010"),
+        (TokenType::INT, "1_000_000"),
+        (TokenType::ILLEGAL, "0x_ff"),
+        (TokenType::FLOAT, "1.5e1_0"),
+        (TokenType::INT, "007"),
+        (TokenType::LBRACKET, "["),
+        (TokenType::FLOAT, "1.2"),
+        (TokenType::RBRACKET, "]"),
+        (TokenType::LBRACKET, "["),
+        (TokenType::INT, "0"),
+        (TokenType::RBRACKET, "]"),
+        (TokenType::EOF, ""),
+    ];
+
+    assert_tokens(&mut lex, expected_tokens);
+}
+
+#[test]
+fn test_read_malformed_numbers() {
+    let mut lex = Lexer::new(String::from("0b102 0x 1__0 1_ 1e 1.5e+ 0o8 12abc 1. 2"));
+
+    let expected_tokens = vec![
+        (TokenType::ILLEGAL, "0b102"),
+        (TokenType::ILLEGAL, "0x"),
+        (TokenType::ILLEGAL, "1__0"),
+        (TokenType::ILLEGAL, "1_"),
+        (TokenType::ILLEGAL, "1e"),
+        (TokenType::ILLEGAL, "1.5e"),
+        (TokenType::PLUS, "+"),
+        (TokenType::ILLEGAL, "0o8"),
+        (TokenType::ILLEGAL, "12abc"),
+        // a dot not followed by a digit isn't part of the number
+        (TokenType::INT, "1"),
+        (TokenType::ILLEGAL, "."),
+        (TokenType::INT, "2"),
+        (TokenType::EOF, ""),
+    ];
+
+    assert_tokens(&mut lex, expected_tokens);
+}
+
+#[test]
+fn test_two_char_operators() {
+    let mut lex = Lexer::new(String::from(
+        "a <= b >= c && d || e % f ** g < h > i & j | k",
+    ));
+
+    let expected_tokens = vec![
+        (TokenType::IDENT, "a"),
+        (TokenType::LTE, "<="),
+        (TokenType::IDENT, "b"),
+        (TokenType::GTE, ">="),
+        (TokenType::IDENT, "c"),
+        (TokenType::AND, "&&"),
+        (TokenType::IDENT, "d"),
+        (TokenType::OR, "||"),
+        (TokenType::IDENT, "e"),
+        (TokenType::PERCENT, "%"),
+        (TokenType::IDENT, "f"),
+        (TokenType::POWER, "**"),
+        (TokenType::IDENT, "g"),
+        (TokenType::LT, "<"),
+        (TokenType::IDENT, "h"),
+        (TokenType::GT, ">"),
+        (TokenType::IDENT, "i"),
+        (TokenType::ILLEGAL, "&"),
+        (TokenType::IDENT, "j"),
+        (TokenType::ILLEGAL, "|"),
+        (TokenType::IDENT, "k"),
+        (TokenType::EOF, ""),
+    ];
+
+    assert_tokens(&mut lex, expected_tokens);
+}
+
+#[test]
+fn test_lexer_diagnostics() {
+    let input = "let a = @;\nlet b = \"x\\qy\\u{zz}\";\nlet c = 0b12 & 1;\n\"open";
+    let mut lex = Lexer::new(input);
+    let kinds: Vec<TokenType> = lex.by_ref().map(|tok| tok.kind).collect();
+    assert_eq!(
+        kinds
+            .iter()
+            .filter(|&kind| *kind == TokenType::ILLEGAL)
+            .count(),
+        5
+    );
+
+    let span = |start: usize, end: usize, line: usize, column: usize| Span {
+        start,
+        end,
+        line,
+        column,
+    };
+    let expected = vec![
+        LexError {
+            kind: LexErrorKind::UnexpectedChar('@'),
+            span: span(8, 9, 1, 9),
+        },
+        LexError {
+            kind: LexErrorKind::InvalidEscape(String::from("\\q")),
+            span: span(21, 23, 2, 11),
+        },
+        LexError {
+            kind: LexErrorKind::InvalidEscape(String::from("\\u")),
+            span: span(24, 26, 2, 14),
+        },
+        LexError {
+            kind: LexErrorKind::MalformedNumber(String::from("0b12")),
+            span: span(41, 45, 3, 9),
+        },
+        LexError {
+            kind: LexErrorKind::UnexpectedChar('&'),
+            span: span(46, 47, 3, 14),
+        },
+        LexError {
+            kind: LexErrorKind::UnterminatedString,
+            span: span(51, 56, 4, 1),
+        },
+    ];
+    assert_eq!(lex.diagnostics(), expected.as_slice());
+
+    let messages: Vec<String> = lex.diagnostics().iter().map(|d| d.to_string()).collect();
+    assert_eq!(
+        messages,
+        vec![
+            "1:9: unexpected character '@'",
+            "2:11: invalid escape sequence `\\q` in string",
+            "2:14: invalid escape sequence `\\u` in string",
+            "3:9: malformed number `0b12`",
+            "3:14: unexpected character '&'",
+            "4:1: unterminated string",
+        ]
+    );
+
+    // the same problems are found without collecting any tokens
+    assert_eq!(diagnose(input), expected);
+}
+
+#[test]
+fn test_lexer_diagnostics_for_comments_and_clean_input() {
+    let errors = diagnose("x /* never closed");
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].to_string(), "1:3: unterminated block comment");
+
+    assert!(diagnose("let x = \"ok\\n\"; // fine").is_empty());
+
+    // resetting starts a fresh list
+    let mut lex = Lexer::new("$");
+    lex.next_token();
+    assert_eq!(lex.diagnostics().len(), 1);
+    lex.reset("x");
+    assert!(lex.diagnostics().is_empty());
 }
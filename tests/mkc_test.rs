@@ -0,0 +1,116 @@
+use monkey::{
+    code::{make, Instructions, Opcode},
+    compiler::{compile, Bytecode},
+    mkc::{decode, encode, FormatError, VERSION},
+    object::Object,
+    parser::parse,
+    vm,
+};
+use std::rc::Rc;
+
+fn compile_input(input: &str) -> Bytecode {
+    let (program, errors) = parse(input);
+    assert!(errors.is_empty(), "parser had errors: {:#?}", errors);
+    compile(&program).expect("compile error")
+}
+
+const PROGRAM: &str = "let greet = fn(name) { \"hi \" + name }; \
+    let scale = fn(x) { fn(y) { x * y * 1.5 } }; \
+    [greet(\"☕\"), scale(-2)(4)]";
+
+#[test]
+fn test_round_trip() {
+    let bytecode = compile_input(PROGRAM);
+    let bytes = encode(&bytecode).unwrap();
+
+    assert_eq!(&bytes[..4], b"MKC\0");
+    assert_eq!(decode(&bytes), Ok(bytecode.clone()));
+    assert_eq!(
+        vm::run(decode(&bytes).unwrap()),
+        Ok(Object::Array(Rc::new(vec![
+            Object::String("hi ☕".to_string()),
+            Object::Float(-12.0)
+        ])))
+    );
+}
+
+#[test]
+fn test_version_mismatch() {
+    let mut bytes = encode(&compile_input(PROGRAM)).unwrap();
+    bytes[4..6].copy_from_slice(&(VERSION + 1).to_be_bytes());
+
+    let err = decode(&bytes).unwrap_err();
+    assert_eq!(err, FormatError::VersionMismatch(VERSION + 1));
+    assert_eq!(
+        err.to_string(),
+        format!(
+            "compiled with bytecode version {}, but this monkey runs version {}; recompile the script",
+            VERSION + 1,
+            VERSION
+        )
+    );
+}
+
+#[test]
+fn test_damaged_files() {
+    let bytes = encode(&compile_input(PROGRAM)).unwrap();
+
+    let mut flipped = bytes.clone();
+    flipped[20] ^= 1;
+    assert_eq!(decode(&flipped), Err(FormatError::ChecksumMismatch));
+
+    assert_eq!(
+        decode(&bytes[..bytes.len() - 1]),
+        Err(FormatError::ChecksumMismatch)
+    );
+    assert_eq!(decode(&bytes[..7]), Err(FormatError::Truncated));
+    assert_eq!(decode(b"let x = 1;"), Err(FormatError::BadMagic));
+}
+
+#[test]
+fn test_unsupported_constant() {
+    let bytecode = Bytecode {
+        instructions: Default::default(),
+        constants: vec![Object::Boolean(true)],
+        spans: Vec::new(),
+    };
+    assert_eq!(
+        encode(&bytecode),
+        Err(FormatError::UnsupportedConstant("BOOLEAN"))
+    );
+}
+
+#[test]
+fn test_invalid_instructions() {
+    let tests = vec![
+        (make(Opcode::Constant, &[80]), "no constant 80 at 0000"),
+        (vec![Opcode::Call as u8], "OpCall is cut short at 0000"),
+        (vec![Opcode::Pop as u8, 200], "unknown opcode 200 at 0001"),
+        (
+            make(Opcode::Closure, &[1, 0]),
+            "constant 1 is not a function at 0000",
+        ),
+        (
+            make(Opcode::GetGlobalChecked, &[0, 1]),
+            "constant 1 is not a name at 0000",
+        ),
+        (
+            make(Opcode::Jump, &[4]),
+            "jump to 0004 is out of bounds at 0000",
+        ),
+    ];
+
+    for (instructions, message) in tests {
+        // A valid checksum doesn't make the instructions trustworthy
+        let bytecode = Bytecode {
+            instructions: Instructions(instructions),
+            constants: vec![Object::Integer(1), Object::Integer(2)],
+            spans: Vec::new(),
+        };
+        let bytes = encode(&bytecode).unwrap();
+        assert_eq!(
+            decode(&bytes),
+            Err(FormatError::InvalidInstructions(message.to_string()))
+        );
+    }
+}
@@ -0,0 +1,631 @@
+use monkey::{
+    ast::*,
+    lexer::Lexer,
+    parser::{parse as parse_with_errors, ParseError, ParseErrorKind, Parser},
+    token::{Span, Token, TokenType},
+};
+
+fn parse(input: &str) -> Program {
+    let mut lex = Lexer::new(String::from(input));
+    let mut parser = Parser::new(&mut lex);
+    let program = parser.parse_program();
+
+    for error in parser.errors() {
+        println!("{}", error);
+    }
+    assert!(parser.errors().is_empty(), "parser had errors");
+    program
+}
+
+fn ident(name: &str) -> Identifier {
+    Identifier::new(name.to_string(), Span::default())
+}
+
+fn ident_expr(name: &str) -> Expression {
+    ExpressionKind::Identifier(ident(name)).into()
+}
+
+fn int(value: i64) -> Expression {
+    ExpressionKind::Integer(value).into()
+}
+
+fn boolean(value: bool) -> Expression {
+    ExpressionKind::Boolean(value).into()
+}
+
+fn string(value: &str) -> Expression {
+    ExpressionKind::String(value.to_string()).into()
+}
+
+fn array(elements: Vec<Expression>) -> Expression {
+    ExpressionKind::Array(elements).into()
+}
+
+fn hash(pairs: Vec<(Expression, Expression)>) -> Expression {
+    ExpressionKind::Hash(pairs).into()
+}
+
+fn prefix(operator: TokenType, right: Expression) -> Expression {
+    ExpressionKind::Prefix {
+        operator,
+        right: Box::new(right),
+    }
+    .into()
+}
+
+fn infix(left: Expression, operator: TokenType, right: Expression) -> Expression {
+    ExpressionKind::Infix {
+        left: Box::new(left),
+        operator,
+        right: Box::new(right),
+    }
+    .into()
+}
+
+fn call(function: Expression, arguments: Vec<Expression>) -> Expression {
+    ExpressionKind::Call {
+        function: Box::new(function),
+        arguments,
+    }
+    .into()
+}
+
+fn single_expression(input: &str) -> Expression {
+    let program = parse(input);
+    assert_eq!(program.statements.len(), 1);
+
+    match program.statements.into_iter().next() {
+        Some(Statement::Expression(expr)) => expr,
+        other => panic!("expected an expression statement, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_let_statements() {
+    let program = parse("let x = 5;\nlet y = true;\nlet foobar = y;");
+
+    let expected = vec![
+        Statement::Let {
+            name: ident("x"),
+            value: int(5),
+        },
+        Statement::Let {
+            name: ident("y"),
+            value: boolean(true),
+        },
+        Statement::Let {
+            name: ident("foobar"),
+            value: ident_expr("y"),
+        },
+    ];
+
+    assert_eq!(program.statements, expected);
+    assert_eq!(program.token_literal(), "let");
+}
+
+#[test]
+fn test_return_statements() {
+    let program = parse("return 5;\nreturn add(1, 2);");
+
+    let expected = vec![
+        Statement::Return(int(5)),
+        Statement::Return(call(ident_expr("add"), vec![int(1), int(2)])),
+    ];
+
+    assert_eq!(program.statements, expected);
+}
+
+#[test]
+fn test_prefix_expressions() {
+    let tests = vec![
+        ("!5;", TokenType::BANG, int(5)),
+        ("-15;", TokenType::MINUS, int(15)),
+        ("!true;", TokenType::BANG, boolean(true)),
+    ];
+
+    for (input, operator, right) in tests {
+        let expected = prefix(operator, right);
+        assert_eq!(single_expression(input), expected);
+    }
+}
+
+#[test]
+fn test_infix_expressions() {
+    let tests = vec![
+        ("5 + 5;", TokenType::PLUS),
+        ("5 - 5;", TokenType::MINUS),
+        ("5 * 5;", TokenType::ASTERISK),
+        ("5 / 5;", TokenType::SLASH),
+        ("5 > 5;", TokenType::GT),
+        ("5 < 5;", TokenType::LT),
+        ("5 == 5;", TokenType::EQ),
+        ("5 != 5;", TokenType::NEQ),
+    ];
+
+    for (input, operator) in tests {
+        let expected = infix(int(5), operator, int(5));
+        assert_eq!(single_expression(input), expected);
+    }
+}
+
+#[test]
+fn test_operator_precedence() {
+    // a + b * c should hang the product off the right side of the sum
+    assert_eq!(
+        single_expression("a + b * c"),
+        infix(
+            ident_expr("a"),
+            TokenType::PLUS,
+            infix(ident_expr("b"), TokenType::ASTERISK, ident_expr("c")),
+        )
+    );
+
+    // grouping overrides the default binding power
+    assert_eq!(
+        single_expression("(a + b) * c"),
+        infix(
+            infix(ident_expr("a"), TokenType::PLUS, ident_expr("b")),
+            TokenType::ASTERISK,
+            ident_expr("c"),
+        )
+    );
+
+    // prefix operators bind tighter than any infix operator
+    assert_eq!(
+        single_expression("-a * b"),
+        infix(
+            prefix(TokenType::MINUS, ident_expr("a")),
+            TokenType::ASTERISK,
+            ident_expr("b"),
+        )
+    );
+}
+
+#[test]
+fn test_if_else_expression() {
+    let expected: Expression = ExpressionKind::If {
+        condition: Box::new(infix(ident_expr("x"), TokenType::LT, ident_expr("y"))),
+        consequence: BlockStatement {
+            statements: vec![Statement::Expression(ident_expr("x"))],
+        },
+        alternative: Some(BlockStatement {
+            statements: vec![Statement::Expression(ident_expr("y"))],
+        }),
+    }
+    .into();
+
+    assert_eq!(single_expression("if (x < y) { x } else { y }"), expected);
+}
+
+#[test]
+fn test_function_literal_and_call() {
+    let program = parse("let add = fn(x, y) { x + y; }; add(1, 2 * 3);");
+
+    let expected = vec![
+        Statement::Let {
+            name: ident("add"),
+            value: ExpressionKind::Function {
+                parameters: vec![ident("x"), ident("y")],
+                body: BlockStatement {
+                    statements: vec![Statement::Expression(infix(
+                        ident_expr("x"),
+                        TokenType::PLUS,
+                        ident_expr("y"),
+                    ))],
+                },
+            }
+            .into(),
+        },
+        Statement::Expression(call(
+            ident_expr("add"),
+            vec![int(1), infix(int(2), TokenType::ASTERISK, int(3))],
+        )),
+    ];
+
+    assert_eq!(program.statements, expected);
+}
+
+#[test]
+fn test_trees_can_be_cloned_and_compared() {
+    let program = parse("let twice = fn(f, x) { f(f(x)) };");
+    let copy = program.clone();
+
+    assert_eq!(program, copy);
+    assert_ne!(program, parse("let twice = fn(f, x) { f(x) };"));
+}
+
+#[test]
+fn test_parser_errors() {
+    let mut lex = Lexer::new(String::from("let = 5; let x 10;"));
+    let mut parser = Parser::new(&mut lex);
+    parser.parse_program();
+
+    let error = |expected: TokenType, found: TokenType, literal: &str, start: usize| {
+        let span = Span {
+            start,
+            end: start + literal.len(),
+            line: 1,
+            column: start + 1,
+        };
+        ParseError {
+            kind: ParseErrorKind::Expected(expected),
+            found: Token {
+                kind: found,
+                literal: literal.to_string(),
+                span,
+            },
+            span,
+        }
+    };
+    let expected = vec![
+        error(TokenType::IDENT, TokenType::ASSIGN, "=", 4),
+        error(TokenType::ASSIGN, TokenType::INT, "10", 15),
+    ];
+
+    assert_eq!(parser.errors(), expected.as_slice());
+    assert_eq!(
+        parser.errors()[0].to_string(),
+        "1:5: expected IDENT, found ASSIGN \"=\""
+    );
+}
+
+#[test]
+fn test_parser_recovers_after_errors() {
+    let (program, errors) =
+        parse_with_errors("let x = 1;\nlet = 2;\nlet f = fn(a { a };\nlet y = (3 + ;\nreturn x;");
+
+    let positions: Vec<(usize, usize)> = errors
+        .iter()
+        .map(|e| (e.span.line, e.span.column))
+        .collect();
+    assert_eq!(positions, vec![(2, 5), (3, 14), (4, 14)]);
+
+    // the well formed statements on either side of the errors survive
+    assert_eq!(program.statements.len(), 2);
+    assert_eq!(
+        program.statements,
+        vec![
+            Statement::Let {
+                name: ident("x"),
+                value: int(1),
+            },
+            Statement::Return(ident_expr("x")),
+        ]
+    );
+}
+
+#[test]
+fn test_parser_recovers_inside_blocks() {
+    let (program, errors) = parse_with_errors("let f = fn() { let = 1; 2 * ; 3 };\nlet y = 4;");
+
+    assert_eq!(errors.len(), 2);
+    assert_eq!(errors[0].kind, ParseErrorKind::Expected(TokenType::IDENT));
+    assert_eq!(errors[1].kind, ParseErrorKind::ExpectedExpression);
+
+    assert_eq!(program.statements.len(), 2);
+    match &program.statements[0] {
+        Statement::Let {
+            value:
+                Expression {
+                    kind: ExpressionKind::Function { body, .. },
+                    ..
+                },
+            ..
+        } => assert_eq!(body.statements, vec![Statement::Expression(int(3))]),
+        other => panic!("expected a function binding, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_unterminated_block() {
+    let (_, errors) = parse_with_errors("if (x) { x");
+
+    assert_eq!(errors.len(), 1);
+    assert_eq!(errors[0].kind, ParseErrorKind::Expected(TokenType::RBRACE));
+    assert_eq!(errors[0].found.kind, TokenType::EOF);
+}
+
+#[test]
+fn test_operator_precedence_rendering() {
+    let tests = vec![
+        ("-a * b", "((-a) * b);"),
+        ("!-a", "(!(-a));"),
+        ("a + b + c", "((a + b) + c);"),
+        ("a + b - c", "((a + b) - c);"),
+        ("a * b * c", "((a * b) * c);"),
+        ("a * b / c", "((a * b) / c);"),
+        ("a + b / c", "(a + (b / c));"),
+        ("a + b * c + d / e - f", "(((a + (b * c)) + (d / e)) - f);"),
+        ("3 + 4; -5 * 5", "(3 + 4);\n((-5) * 5);"),
+        ("5 > 4 == 3 < 4", "((5 > 4) == (3 < 4));"),
+        ("5 < 4 != 3 > 4", "((5 < 4) != (3 > 4));"),
+        (
+            "3 + 4 * 5 == 3 * 1 + 4 * 5",
+            "((3 + (4 * 5)) == ((3 * 1) + (4 * 5)));",
+        ),
+        ("true == !false", "(true == (!false));"),
+        ("1 + (2 + 3) + 4", "((1 + (2 + 3)) + 4);"),
+        ("-(5 + 5)", "(-(5 + 5));"),
+        ("a + add(b * c) + d", "((a + add((b * c))) + d);"),
+        (
+            "add(a, b, 1, 2 * 3, 4 + 5, add(6, 7 * 8))",
+            "add(a, b, 1, (2 * 3), (4 + 5), add(6, (7 * 8)));",
+        ),
+        (
+            "add(a + b + c * d / f + g)",
+            "add((((a + b) + ((c * d) / f)) + g));",
+        ),
+    ];
+
+    for (input, expected) in tests {
+        assert_eq!(parse(input).to_string(), expected, "input: {}", input);
+    }
+}
+
+#[test]
+fn test_program_display_round_trips() {
+    let input = "let add = fn(x, y) { return x + y; };
+        let apply = fn(f) { f(1, 2) };
+        if (apply(add) > 2) { true } else { let nothing = fn() {}; false }
+        fn(x) { x }(-5)";
+
+    let program = parse(input);
+    let rendered = program.to_string();
+
+    assert_eq!(
+        rendered,
+        "let add = fn(x, y) { return (x + y); };
+let apply = fn(f) { f(1, 2); };
+if ((apply(add) > 2)) { true; } else { let nothing = fn() {}; false; };
+fn(x) { x; }((-5));"
+    );
+
+    // the rendering parses back into the same tree and is a fixed point
+    let reparsed = parse(&rendered);
+    assert_eq!(reparsed, program);
+    assert_eq!(reparsed.to_string(), rendered);
+}
+
+#[test]
+fn test_string_literal() {
+    assert_eq!(
+        single_expression(r#""hello world";"#),
+        string("hello world")
+    );
+
+    // escapes are rendered back in a form the lexer reads the same way
+    let program = parse(r#"let s = "a \"quoted\"\tword\n" + "\\";"#);
+    assert_eq!(
+        program.to_string(),
+        r#"let s = ("a \"quoted\"\tword\n" + "\\");"#
+    );
+    assert_eq!(parse(&program.to_string()), program);
+}
+
+#[test]
+fn test_array_and_index_expressions() {
+    assert_eq!(
+        single_expression("[1, 2 * 2, \"three\"]"),
+        array(vec![
+            int(1),
+            infix(int(2), TokenType::ASTERISK, int(2)),
+            string("three"),
+        ])
+    );
+    assert_eq!(single_expression("[]"), array(vec![]));
+
+    assert_eq!(
+        single_expression("myArray[1 + 1]"),
+        ExpressionKind::Index {
+            left: Box::new(ident_expr("myArray")),
+            index: Box::new(infix(int(1), TokenType::PLUS, int(1))),
+        }
+        .into()
+    );
+
+    let tests = vec![
+        (
+            "a * [1, 2, 3, 4][b * c] * d",
+            "((a * ([1, 2, 3, 4][(b * c)])) * d);",
+        ),
+        (
+            "add(a * b[2], b[1], 2 * [1, 2][1])",
+            "add((a * (b[2])), (b[1]), (2 * ([1, 2][1])));",
+        ),
+        ("f(x)[0]", "(f(x)[0]);"),
+    ];
+    for (input, expected) in tests {
+        assert_eq!(parse(input).to_string(), expected, "input: {}", input);
+    }
+}
+
+#[test]
+fn test_hash_literals() {
+    assert_eq!(
+        single_expression(r#"{"one": 1, true: 2, 3: 1 + 2}"#),
+        hash(vec![
+            (string("one"), int(1)),
+            (boolean(true), int(2)),
+            (int(3), infix(int(1), TokenType::PLUS, int(2))),
+        ])
+    );
+    assert_eq!(single_expression("{}"), hash(vec![]));
+
+    let program = parse(r#"let h = {"a": [1, 2], "b": {}}; h["a"][0]"#);
+    assert_eq!(
+        program.to_string(),
+        r#"let h = {"a": [1, 2], "b": {}};
+((h["a"])[0]);"#
+    );
+    assert_eq!(parse(&program.to_string()), program);
+
+    let (_, errors) = parse_with_errors(r#"{"a" 1}"#);
+    assert_eq!(errors[0].kind, ParseErrorKind::Expected(TokenType::COLON));
+}
+
+#[test]
+fn test_expression_spans() {
+    let input = "let total = add(1, 2 * x)[0];\n(-a + b)";
+    let program = parse(input);
+
+    let source_of = |expr: &Expression| &input[expr.span.start..expr.span.end];
+
+    match &program.statements[0] {
+        Statement::Let { name, value } => {
+            assert_eq!(&input[name.span.start..name.span.end], "total");
+            assert_eq!((name.span.line, name.span.column), (1, 5));
+            assert_eq!(source_of(value), "add(1, 2 * x)[0]");
+            assert_eq!((value.span.line, value.span.column), (1, 13));
+
+            match &value.kind {
+                ExpressionKind::Index { left, index } => {
+                    assert_eq!(source_of(left), "add(1, 2 * x)");
+                    assert_eq!(source_of(index), "0");
+
+                    match &left.kind {
+                        ExpressionKind::Call { arguments, .. } => {
+                            assert_eq!(source_of(&arguments[1]), "2 * x");
+                        }
+                        other => panic!("expected a call, got {:?}", other),
+                    }
+                }
+                other => panic!("expected an index expression, got {:?}", other),
+            }
+        }
+        other => panic!("expected a let statement, got {:?}", other),
+    }
+
+    // grouping parentheses are part of the span of what they enclose
+    match &program.statements[1] {
+        Statement::Expression(expr) => {
+            assert_eq!(source_of(expr), "(-a + b)");
+            assert_eq!((expr.span.line, expr.span.column), (2, 1));
+        }
+        other => panic!("expected an expression statement, got {:?}", other),
+    }
+
+    // parameters point at their names, though identifiers compare by name alone
+    let program = parse("fn(first,\n  second) { second }");
+    match &program.statements[0] {
+        Statement::Expression(Expression {
+            kind: ExpressionKind::Function { parameters, .. },
+            ..
+        }) => {
+            let spans: Vec<(usize, usize)> = parameters
+                .iter()
+                .map(|param| (param.span.line, param.span.column))
+                .collect();
+            assert_eq!(spans, vec![(1, 4), (2, 3)]);
+            assert_eq!(parameters[1], ident("second"));
+        }
+        other => panic!("expected a function literal, got {:?}", other),
+    }
+}
+
+#[test]
+fn test_parser_lookahead() {
+    let mut lex = Lexer::new(String::from("let x = 5;"));
+    let mut parser = Parser::new(&mut lex);
+
+    let kinds: Vec<TokenType> = (0..7).map(|n| parser.peek_nth(n).kind.clone()).collect();
+    assert_eq!(
+        kinds,
+        vec![
+            TokenType::LET,
+            TokenType::IDENT,
+            TokenType::ASSIGN,
+            TokenType::INT,
+            TokenType::SEMICOLON,
+            TokenType::EOF,
+            TokenType::EOF,
+        ]
+    );
+
+    // peeking doesn't consume, so the program still parses whole
+    let program = parser.parse_program();
+    assert!(parser.errors().is_empty());
+    assert_eq!(program.to_string(), "let x = 5;");
+}
+
+#[test]
+fn test_number_literals() {
+    let tests = vec![
+        ("0xff", ExpressionKind::Integer(255)),
+        ("0o17", ExpressionKind::Integer(15)),
+        ("0b1010", ExpressionKind::Integer(10)),
+        ("1_000_000", ExpressionKind::Integer(1_000_000)),
+        ("9223372036854775807", ExpressionKind::Integer(i64::MAX)),
+        ("2.75", ExpressionKind::Float(2.75)),
+        ("1e-9", ExpressionKind::Float(1e-9)),
+        ("1_0.2_5", ExpressionKind::Float(10.25)),
+    ];
+
+    for (input, expected) in tests {
+        assert_eq!(single_expression(input).kind, expected, "input: {}", input);
+    }
+
+    // floats always print with a fraction or exponent so they read back as floats
+    assert_eq!(parse("2.0 * 1e-9;").to_string(), "(2.0 * 1e-9);");
+    assert_eq!(parse("-0x10;").to_string(), "(-16);");
+}
+
+#[test]
+fn test_integer_overflow_error() {
+    for input in &[
+        "9223372036854775808",
+        "0x8000000000000000",
+        "1_000_000_000_000_000_000_000",
+    ] {
+        let (_, errors) = parse_with_errors(input);
+        assert_eq!(errors.len(), 1, "input: {}", input);
+        assert_eq!(errors[0].kind, ParseErrorKind::IntegerOverflow);
+        assert_eq!(
+            errors[0].to_string(),
+            format!(
+                "1:1: integer literal is larger than 9223372036854775807, found INT {:?}",
+                input
+            )
+        );
+    }
+}
+
+#[test]
+fn test_float_overflow_error() {
+    for input in &["1e311", "1_000e400", "2.5e308"] {
+        let (_, errors) = parse_with_errors(input);
+        assert_eq!(errors.len(), 1, "input: {}", input);
+        assert_eq!(errors[0].kind, ParseErrorKind::FloatOverflow);
+        assert_eq!(
+            errors[0].to_string(),
+            format!(
+                "1:1: float literal is larger than 1.7976931348623157e308, found FLOAT {:?}",
+                input
+            )
+        );
+    }
+
+    // Tiny literals round to zero, which still reads back as a float
+    assert_eq!(single_expression("1e-400").kind, ExpressionKind::Float(0.0));
+}
+
+#[test]
+fn test_logical_and_arithmetic_precedence() {
+    let tests = vec![
+        ("a || b && c", "(a || (b && c));"),
+        ("a && b || c && d", "((a && b) || (c && d));"),
+        ("a == b && c != d", "((a == b) && (c != d));"),
+        ("a <= b == c >= d", "((a <= b) == (c >= d));"),
+        ("a + b % c", "(a + (b % c));"),
+        ("a * b % c", "((a * b) % c);"),
+        ("a ** b ** c", "(a ** (b ** c));"),
+        ("a * b ** c", "(a * (b ** c));"),
+        ("-a ** b", "(-(a ** b));"),
+        ("a ** -b", "(a ** (-b));"),
+        ("!a || b", "((!a) || b);"),
+        ("a[0] ** f(b)", "((a[0]) ** f(b));"),
+    ];
+
+    for (input, expected) in tests {
+        assert_eq!(parse(input).to_string(), expected, "input: {}", input);
+    }
+}
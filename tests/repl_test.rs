@@ -0,0 +1,151 @@
+use monkey::{repl::Repl, script::Engine};
+use std::io::Cursor;
+
+fn run(input: &str) -> String {
+    let repl = Repl::new(String::from(">>"));
+    let mut output = Vec::new();
+
+    repl.run_with(Cursor::new(input), &mut output).unwrap();
+    String::from_utf8(output).unwrap()
+}
+
+#[test]
+fn test_repl_evaluates_lines_against_one_environment() {
+    let output = run("let add = fn(a, b) { a + b };\nadd(1, 2)\n\n\"mon\" + \"key\"\n");
+
+    assert_eq!(
+        output,
+        "Monkey Lang v0.1 - REPL\n>> >> 3\n>> >> monkey\n>> "
+    );
+}
+
+#[test]
+fn test_repl_prints_errors() {
+    let output = run("let = 5;\n1 + true\n");
+
+    assert_eq!(
+        output,
+        "Monkey Lang v0.1 - REPL
+>> Woops! We ran into some monkey business here!
+ parser errors:
+\t1:5: expected IDENT, found ASSIGN \"=\"
+>> ERROR: type mismatch: INTEGER + BOOLEAN
+>> "
+    );
+}
+
+#[test]
+fn test_repl_token_dump() {
+    let output = run(":tokens let x = 5;\n:tokens\n");
+
+    assert_eq!(
+        output,
+        "Monkey Lang v0.1 - REPL
+>> LET
+IDENT(\"x\")
+ASSIGN
+INT(\"5\")
+SEMICOLON
+>> >> "
+    );
+}
+
+#[test]
+fn test_repl_survives_blank_and_invalid_lines() {
+    let repl = Repl::new(String::from(">>"));
+    let mut output = Vec::new();
+
+    repl.run_with(Cursor::new(&b":tokens\n\n\xff\n1\n"[..]), &mut output)
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "Monkey Lang v0.1 - REPL
+>> >> >> 1:1: source is not valid UTF-8
+>> 1
+>> "
+    );
+}
+
+#[test]
+fn test_repl_token_dump_reports_lexer_errors() {
+    let output = run(":tokens 1 ~ 2\n");
+
+    assert_eq!(
+        output,
+        "Monkey Lang v0.1 - REPL
+>> INT(\"1\")
+ILLEGAL
+INT(\"2\")
+error: 1:4: unexpected character '~'
+>> "
+    );
+}
+
+#[test]
+fn test_repl_prints_lexer_errors_first() {
+    let output = run("let y = \"a\\q\";\n");
+
+    assert_eq!(
+        output,
+        "Monkey Lang v0.1 - REPL
+>> Woops! We ran into some monkey business here!
+ lexer errors:
+\t1:11: invalid escape sequence `\\q` in string
+ parser errors:
+\t1:9: expected an expression, found ILLEGAL \"\\\"a\\\\q\\\"\"
+>> "
+    );
+}
+
+#[test]
+fn test_repl_on_the_vm() {
+    let repl = Repl::new(String::from(">>")).engine(Engine::Vm);
+    let mut output = Vec::new();
+
+    repl.run_with(
+        Cursor::new("let add = fn(a, b) { a + b };\nadd(1, 2)\nlet x = add(x, 1);\nlen(\"four\") * 2\n1 + true\n"),
+        &mut output,
+    )
+    .unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "Monkey Lang v0.1 - REPL
+>> >> 3
+>> ERROR: identifier not found: x
+>> 8
+>> ERROR: type mismatch: INTEGER + BOOLEAN
+>> "
+    );
+}
+
+#[test]
+fn test_repl_colors_errors() {
+    let repl = Repl::new(String::from(">>")).color(true);
+    let mut output = Vec::new();
+
+    repl.run_with(Cursor::new("1\n-true\n"), &mut output)
+        .unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "Monkey Lang v0.1 - REPL\n>> 1\n>> \x1b[31mERROR: unknown operator: -BOOLEAN\x1b[0m\n>> "
+    );
+}
+
+#[test]
+fn test_repl_on_the_vm_forgets_lines_that_fail_to_compile() {
+    let repl = Repl::new(String::from(">>")).engine(Engine::Vm);
+    let mut output = Vec::new();
+
+    // One argument more than a call can take
+    let args = vec!["0"; 256].join(", ");
+    let input = format!("let a = 1; let b = len({});\na\nlet a = 2;\na\n", args);
+    repl.run_with(Cursor::new(input), &mut output).unwrap();
+    assert_eq!(
+        String::from_utf8(output).unwrap(),
+        "Monkey Lang v0.1 - REPL
+>> ERROR: too many arguments in one call, the limit is 255
+>> ERROR: identifier not found: a
+>> >> 2
+>> "
+    );
+}
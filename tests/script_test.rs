@@ -0,0 +1,73 @@
+use monkey::{
+    object::Object,
+    script::{run, run_with, Engine, ScriptError},
+};
+
+fn messages(errors: Vec<ScriptError>) -> Vec<String> {
+    errors.iter().map(|err| err.to_string()).collect()
+}
+
+#[test]
+fn test_run_script() {
+    let source = "let double = fn(x) { x * 2 };\nlet xs = [1, 2, 3];\ndouble(last(xs))\n";
+    assert_eq!(run("double.mk", source), Ok(Object::Integer(6)));
+    assert_eq!(run("empty.mk", ""), Ok(Object::Null));
+}
+
+#[test]
+fn test_script_errors_name_the_file() {
+    let errors = run("bad.mk", "let x = 1;\nlet = 2;\nlet y 3;\n").unwrap_err();
+    assert_eq!(
+        messages(errors),
+        vec![
+            "bad.mk:2:5: expected IDENT, found ASSIGN \"=\"",
+            "bad.mk:3:7: expected ASSIGN, found INT \"3\"",
+        ]
+    );
+
+    let errors = run("-e", "let f = fn(x) {\n  x + true\n};\nf(1)").unwrap_err();
+    assert_eq!(
+        messages(errors),
+        vec!["-e:2:3: type mismatch: INTEGER + BOOLEAN"]
+    );
+}
+
+#[test]
+fn test_lexer_errors_come_first() {
+    let errors = run("bad.mk", "let s = \"abc").unwrap_err();
+    assert_eq!(messages(errors)[0], "bad.mk:1:9: unterminated string");
+
+    let errors = run("bad.mk", "let x = 1 @ 2;").unwrap_err();
+    assert_eq!(messages(errors)[0], "bad.mk:1:11: unexpected character '@'");
+}
+
+#[test]
+fn test_run_script_on_the_vm() {
+    let source = "let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } };\nfib(10)";
+    assert_eq!(
+        run_with(Engine::Vm, "fib.mk", source),
+        Ok(Object::Integer(55))
+    );
+
+    let errors = run_with(Engine::Vm, "bad.mk", "let a = 1;\na + b").unwrap_err();
+    assert_eq!(
+        messages(errors),
+        vec!["bad.mk:2:5: identifier not found: b"]
+    );
+
+    let errors = run_with(Engine::Vm, "bad.mk", "[1][\"a\"]").unwrap_err();
+    assert_eq!(
+        messages(errors),
+        vec!["bad.mk:1:1: index operator not supported: ARRAY"]
+    );
+
+    // Runtime errors point at the same expression the evaluator would
+    let source = "let f = fn(x) {\n  x + true\n};\nf(1)";
+    for engine in [Engine::Eval, Engine::Vm].iter() {
+        let errors = run_with(*engine, "-e", source).unwrap_err();
+        assert_eq!(
+            messages(errors),
+            vec!["-e:2:3: type mismatch: INTEGER + BOOLEAN"]
+        );
+    }
+}
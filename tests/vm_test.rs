@@ -0,0 +1,206 @@
+use monkey::{
+    code::{make, Instructions, Opcode},
+    compiler::{compile, Bytecode},
+    eval::eval_program,
+    object::{Environment, Object, RuntimeError},
+    parser::parse,
+    vm,
+};
+use std::cell::RefCell;
+use std::rc::Rc;
+
+fn run(input: &str) -> Result<Object, RuntimeError> {
+    let (program, errors) = parse(input);
+    assert!(errors.is_empty(), "parser had errors: {:#?}", errors);
+
+    let bytecode = compile(&program).expect("compile error");
+    vm::run(bytecode)
+}
+
+fn run_ok(input: &str) -> Object {
+    run(input).unwrap_or_else(|err| panic!("input: {}, error: {}", input, err))
+}
+
+#[test]
+fn test_integer_arithmetic() {
+    let tests = vec![
+        ("1 + 2", 3),
+        ("50 / 2 * 2 + 10 - 5", 55),
+        ("-50 + 100 + -50", 0),
+        ("(5 + 10 * 2 + 15 / 3) * 2 + -10", 50),
+        ("7 % 3", 1),
+        ("2 ** 3 ** 2", 512),
+    ];
+
+    for (input, expected) in tests {
+        assert_eq!(run_ok(input), Object::Integer(expected), "input: {}", input);
+    }
+}
+
+#[test]
+fn test_boolean_expressions() {
+    let tests = vec![
+        ("1 < 2", true),
+        ("1 >= 2", false),
+        ("2 <= 2", true),
+        ("(1 > 2) == false", true),
+        ("!(if (false) { 5; })", true),
+        ("true && 0 != 0", false),
+        ("false || 1", true),
+        ("\"a\" == \"a\"", true),
+    ];
+
+    for (input, expected) in tests {
+        assert_eq!(run_ok(input), Object::Boolean(expected), "input: {}", input);
+    }
+}
+
+#[test]
+fn test_conditionals() {
+    let tests = vec![
+        ("if (true) { 10 }", Object::Integer(10)),
+        ("if (1 > 2) { 10 } else { 20 }", Object::Integer(20)),
+        ("if (1 > 2) { 10 }", Object::Null),
+        (
+            "if (if (false) { 10 }) { 10 } else { 20 }",
+            Object::Integer(20),
+        ),
+    ];
+
+    for (input, expected) in tests {
+        assert_eq!(run_ok(input), expected, "input: {}", input);
+    }
+}
+
+#[test]
+fn test_short_circuit() {
+    // The right side would be an error if it were evaluated
+    assert_eq!(run_ok("false && 1 / 0"), Object::Boolean(false));
+    assert_eq!(run_ok("true || 1 / 0"), Object::Boolean(true));
+}
+
+#[test]
+fn test_collections() {
+    assert_eq!(
+        run_ok("[1, 2 * 2, 3 + 3]"),
+        Object::Array(Rc::new(vec![
+            Object::Integer(1),
+            Object::Integer(4),
+            Object::Integer(6)
+        ]))
+    );
+    assert_eq!(run_ok("[1, 2, 3][1 + 1]"), Object::Integer(3));
+    assert_eq!(run_ok("{1: 2, \"a\": 3}[\"a\"]"), Object::Integer(3));
+    assert_eq!(run_ok("[][0]"), Object::Null);
+    assert_eq!(run_ok("len(push([1], 2))"), Object::Integer(2));
+}
+
+#[test]
+fn test_functions_and_closures() {
+    let tests = vec![
+        ("let f = fn() { 5 + 10 }; f()", 15),
+        ("let f = fn(a, b) { let c = a + b; c * 2 }; f(1, 2)", 6),
+        ("let f = fn() { return 1; 2 }; f()", 1),
+        ("let g = 50; let f = fn(a) { a + g }; f(1) + f(2)", 103),
+        (
+            "let adder = fn(x) { fn(y) { x + y } }; let addTwo = adder(2); addTwo(3)",
+            5,
+        ),
+        (
+            "let f = fn(a) { fn(b) { fn(c) { a + b + c } } }; f(1)(2)(3)",
+            6,
+        ),
+        (
+            "let fib = fn(n) { if (n < 2) { n } else { fib(n - 1) + fib(n - 2) } }; fib(15)",
+            610,
+        ),
+        (
+            "let wrapper = fn() { let countdown = fn(x) { if (x == 0) { 0 } else { countdown(x - 1) } }; countdown(5) }; wrapper()",
+            0,
+        ),
+        ("let x = 1; return 7; 9", 7),
+    ];
+
+    for (input, expected) in tests {
+        assert_eq!(run_ok(input), Object::Integer(expected), "input: {}", input);
+    }
+}
+
+#[test]
+fn test_runtime_errors() {
+    let tests = vec![
+        ("5 + true", "type mismatch: INTEGER + BOOLEAN"),
+        ("-true", "unknown operator: -BOOLEAN"),
+        ("1 / 0", "division by zero"),
+        ("fn(a) { a }()", "wrong number of arguments: want=1, got=0"),
+        ("1(2)", "not a function: INTEGER"),
+        ("{fn() {}: 1}", "unusable as hash key: FUNCTION"),
+        ("let f = fn() { f() }; f()", "stack overflow"),
+        (
+            "let f = fn() { g }; f(); let g = 1;",
+            "identifier not found: g",
+        ),
+        ("let x = x;", "identifier not found: x"),
+    ];
+
+    for (input, expected) in tests {
+        let err = run(input).unwrap_err();
+        assert_eq!(err.message, expected, "input: {}", input);
+    }
+}
+
+#[test]
+fn test_matches_evaluator() {
+    let inputs = vec![
+        "let map = fn(arr, f) { let iter = fn(arr, acc) { if (len(arr) == 0) { acc } else { iter(rest(arr), push(acc, f(first(arr)))) } }; iter(arr, []) }; map([1, 2, 3], fn(x) { x * x })",
+        "let h = {\"one\": 1, true: 2.5}; h[true] * 2",
+        "\"mon\" + \"key\"",
+        "1.5 + 2",
+        "2 ** -1",
+        "last([1, 2, 3]) % 2 == 1",
+        "let isEven = fn(n) { if (n == 0) { true } else { isOdd(n - 1) } }; let isOdd = fn(n) { if (n == 0) { false } else { isEven(n - 1) } }; [isEven(10), isOdd(7)]",
+        "if (false) { undefined_x } else { 1 }",
+    ];
+
+    for input in inputs {
+        let (program, _) = parse(input);
+        let env = Rc::new(RefCell::new(Environment::new()));
+        assert_eq!(
+            run_ok(input),
+            eval_program(&program, &env),
+            "input: {}",
+            input
+        );
+    }
+}
+
+#[test]
+fn test_invalid_bytecode() {
+    let tests = vec![
+        (
+            vec![make(Opcode::True, &[]), vec![255]],
+            "invalid bytecode: unknown opcode 255 at 0001",
+        ),
+        (
+            vec![make(Opcode::Add, &[])],
+            "invalid bytecode: pop from an empty stack",
+        ),
+        (
+            vec![make(Opcode::Constant, &[80])],
+            "invalid bytecode: no constant 80",
+        ),
+        (
+            vec![make(Opcode::True, &[]), vec![Opcode::Call as u8]],
+            "invalid bytecode: operand cut short at 0002",
+        ),
+    ];
+
+    for (instructions, message) in tests {
+        let bytecode = Bytecode {
+            instructions: Instructions(instructions.concat()),
+            constants: Vec::new(),
+            spans: Vec::new(),
+        };
+        assert_eq!(vm::run(bytecode).unwrap_err().message, message);
+    }
+}